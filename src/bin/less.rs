@@ -4,167 +4,647 @@
 //! well as forward movement. Also, less does not have to read the entire input file before
 //! starting, so with large input files it starts up faster than text editors like vi (1)
 //!
+//! Regular files are memory mapped. Standard input and other streams are read on a background
+//! thread that stays a few chunks ahead of the pager, so an endless pipe is only read as far as
+//! it is scrolled. Keys are read from the controlling terminal, any key interrupts waiting for
+//! a stream, e.g. when searching it or jumping to its end.
+//!
 //! Todo:
-//!  - search backwards
-//!  - handle terminal resize
-//!  - handle stdin, handle appears to close after draining bufread
-//!  - page up/down, tailing, etc
+//!  - tailing, etc
 //!  - many other things
 
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::{IntoRawMode, RawTerminal};
 use termion::screen::AlternateScreen;
-use termion::{clear, color, cursor, terminal_size};
+use termion::{clear, color, cursor, get_tty, is_tty, terminal_size};
 
 use memmap::{Mmap, MmapOptions};
+use nix::sys::signal::{SigSet, Signal};
 use regex::bytes::Regex;
 
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{stdin, stdout, Write};
+use std::io::{self, stdin, stdout, Read, Write};
 use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+use coreutils::io::{Input, InputArg};
 use coreutils::util::{emit_bell, print_help_and_exit};
 
-const USAGE: &str = "less <filename>: opposite of more";
+const USAGE: &str = "less [FILE]: opposite of more, shows standard input without FILE";
+
+/// Size of the chunks read from a stream
+const CHUNK_SIZE: usize = 1 << 16;
+/// Number of chunks a stream is read ahead of the pager
+const READ_AHEAD: usize = 4;
+/// Interval at which waiting for content checks for keys that interrupt it
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Initial size of the window scanned per step when searching backwards
+const SEARCH_WINDOW: usize = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+impl Direction {
+    fn reverse(self) -> Self {
+        match self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+        }
+    }
+}
+
+/// Input for the main loop
+enum Event {
+    Key(io::Result<Key>),
+    /// a stream has read more content
+    Data,
+    /// the terminal was resized
+    Resize,
+}
+
+/// Content shown by the pager
+enum Store {
+    /// Regular file, memory mapped
+    Mapped(Mmap),
+    /// Standard input, pipes and other files without a size, read on a background thread
+    Streamed(Stream),
+}
+
+struct Stream {
+    data: Vec<u8>,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    done: bool,
+    error: Option<io::Error>,
+}
+
+impl Store {
+    fn open(input: Input, events: Sender<Event>) -> io::Result<Self> {
+        match input {
+            Input::File(file) if Self::mappable(&file)? => {
+                let mmap = unsafe { MmapOptions::new().map(&file)? };
+                Ok(Store::Mapped(mmap))
+            }
+            input => Ok(Store::stream(input.into_read(), events)),
+        }
+    }
+
+    /// Regular files can be mapped, except for empty files and files that report no size
+    fn mappable(file: &File) -> io::Result<bool> {
+        let metadata = file.metadata()?;
+        Ok(metadata.is_file() && metadata.len() > 0)
+    }
+
+    /// Read `read` on a background thread, which blocks once it is `READ_AHEAD` chunks ahead
+    fn stream(mut read: Box<dyn Read + Send>, events: Sender<Event>) -> Self {
+        let (sender, chunks) = sync_channel(READ_AHEAD);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let result = match read.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(chunk)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+
+            let failed = result.is_err();
+            if sender.send(result).is_err() || events.send(Event::Data).is_err() || failed {
+                break;
+            }
+        });
+
+        Store::Streamed(Stream {
+            data: vec![],
+            chunks,
+            done: false,
+            error: None,
+        })
+    }
+
+    fn data(&self) -> &[u8] {
+        match self {
+            Store::Mapped(mmap) => mmap,
+            Store::Streamed(stream) => &stream.data,
+        }
+    }
+
+    /// Append a chunk received from the stream, returns false if there is no more content
+    fn append(stream: &mut Stream, chunk: io::Result<Vec<u8>>) -> bool {
+        match chunk {
+            Ok(chunk) => {
+                stream.data.extend_from_slice(&chunk);
+                true
+            }
+            Err(e) => {
+                stream.error = Some(e);
+                stream.done = true;
+                false
+            }
+        }
+    }
+
+    /// Load more content, waiting at most `timeout` for it to be read. Returns `None` if nothing
+    /// was read in time, otherwise false at the end of the content
+    fn load_within(&mut self, timeout: Duration) -> Option<bool> {
+        let stream = match self {
+            Store::Streamed(stream) if !stream.done => stream,
+            _ => return Some(false),
+        };
+
+        match stream.chunks.recv_timeout(timeout) {
+            Ok(chunk) => Some(Self::append(stream, chunk)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                stream.done = true;
+                Some(false)
+            }
+        }
+    }
+
+    /// Load content that has been read already until `len` bytes are available, without
+    /// waiting. Returns whether anything was loaded
+    fn load_available(&mut self, len: usize) -> bool {
+        let stream = match self {
+            Store::Streamed(stream) => stream,
+            _ => return false,
+        };
+
+        let mut loaded = false;
+        while !stream.done && stream.data.len() < len {
+            match stream.chunks.try_recv() {
+                Ok(chunk) => loaded |= Self::append(stream, chunk),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => stream.done = true,
+            }
+        }
+
+        loaded
+    }
+
+    /// All content has been loaded
+    fn complete(&self) -> bool {
+        match self {
+            Store::Mapped(_) => true,
+            Store::Streamed(stream) => stream.done,
+        }
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        match self {
+            Store::Streamed(stream) => stream.error.take(),
+            _ => None,
+        }
+    }
+}
 
 struct Pager {
-    mmap: Mmap,
+    store: Store,
     size: (u16, u16),
     scroll_pos: usize,
     cursor: usize,
     search: Option<Regex>,
+    direction: Direction,
+    /// top line when the pattern being typed was started
+    search_origin: Option<usize>,
+    /// message to show on the status line after the next draw
+    notice: Option<String>,
+    /// number of keys read from the terminal that are not handled yet, these interrupt waiting
+    /// for content
+    keys_pending: Arc<AtomicUsize>,
 }
 
 impl Pager {
-    fn new(mmap: Mmap, size: (u16, u16)) -> Self {
+    fn new(store: Store, size: (u16, u16)) -> Self {
         Self {
-            mmap,
+            store,
             size,
             scroll_pos: 0,
             cursor: 0,
             search: None,
+            direction: Direction::Forward,
+            search_origin: None,
+            notice: None,
+            keys_pending: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    fn jump_to_top(&mut self) -> bool {
-        if self.scroll_pos != 0 {
-            self.scroll_pos = 0;
-            self.cursor = 0;
-            true
+    /// Use the new terminal size, the top line stays in place
+    fn resize(&mut self, size: (u16, u16)) {
+        self.size = size;
+    }
+
+    /// Number of terminal rows available for content, the last row shows the status
+    fn rows(&self) -> usize {
+        (self.size.1 as usize).saturating_sub(1).max(1)
+    }
+
+    /// A key was pressed while waiting for content
+    fn interrupted(&self) -> bool {
+        self.keys_pending.load(Ordering::SeqCst) > 0
+    }
+
+    /// Load more content, waiting for it to be read unless a key is pressed. Returns false at
+    /// the end of the content or when interrupted
+    fn load_more(&mut self) -> bool {
+        while !self.interrupted() {
+            if let Some(more) = self.store.load_within(POLL_INTERVAL) {
+                return more;
+            }
+        }
+
+        false
+    }
+
+    /// Load all content, waiting for the stream to end unless a key is pressed. Returns false
+    /// when interrupted
+    fn load_all(&mut self) -> bool {
+        while self.load_more() {}
+        self.store.complete()
+    }
+
+    /// Start of the line containing `pos`
+    fn line_start(&self, pos: usize) -> usize {
+        self.store.data()[..pos]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map(|index| index + 1)
+            .unwrap_or(0)
+    }
+
+    /// Position of the first newline at or after `pos`, loading content as needed
+    fn find_newline(&mut self, pos: usize) -> Option<usize> {
+        let mut from = pos;
+        loop {
+            let data = self.store.data();
+            if let Some(index) = data[from..].iter().position(|c| *c == b'\n') {
+                return Some(from + index);
+            }
+
+            from = data.len();
+            if !self.load_more() {
+                return None;
+            }
+        }
+    }
+
+    /// Start of the line following the line at `pos`, if there is any content after it
+    fn next_line(&mut self, pos: usize) -> Option<usize> {
+        let next = self.find_newline(pos)? + 1;
+        while next >= self.store.data().len() {
+            if !self.load_more() {
+                return None;
+            }
+        }
+
+        Some(next)
+    }
+
+    /// Start of the line preceding the line at `pos`
+    fn prev_line(&self, pos: usize) -> Option<usize> {
+        if pos == 0 {
+            None
         } else {
-            false
+            Some(self.line_start(pos - 1))
         }
     }
 
+    /// Number of terminal rows taken by the line at `pos`, long lines are wrapped
+    fn line_rows(&mut self, pos: usize) -> usize {
+        let len = match self.find_newline(pos) {
+            Some(newline) => newline - pos,
+            None => self.store.data().len() - pos,
+        };
+        let width = self.size.0.max(1) as usize;
+
+        len.div_ceil(width).max(1)
+    }
+
+    /// Show the line at `pos` at the top, returns false if nothing changed
+    fn set_top(&mut self, pos: usize) -> bool {
+        if pos == self.scroll_pos {
+            return false;
+        }
+
+        self.scroll_pos = pos;
+        self.cursor = pos;
+        true
+    }
+
+    fn jump_to_top(&mut self) -> bool {
+        self.set_top(0)
+    }
+
+    /// Show the last lines of the file, filling the screen. When interrupted this shows the
+    /// last lines loaded so far
     fn jump_to_bottom(&mut self) -> bool {
-        if self.mmap.len() <= 1 {
+        if !self.load_all() {
+            self.notice = Some("Interrupted".into());
+        }
+        let len = self.store.data().len();
+        if len == 0 {
             return false;
         }
 
-        let mut height = 10; // todo
-        let newline_pos = self.mmap[..(self.mmap.len() - 1)].iter().rposition(|c| {
-            if *c == b'\n' {
-                height -= 1;
-            }
-            height == 0
-        });
-        if let Some(index) = newline_pos {
-            let new_scroll_pos = index + 1;
-            if new_scroll_pos >= self.mmap.len() {
-                false
-            } else {
-                self.scroll_pos = new_scroll_pos;
-                self.cursor = new_scroll_pos;
-                true
+        let rows = self.rows();
+        let mut pos = self.line_start(len - 1);
+        let mut used = self.line_rows(pos);
+        while let Some(prev) = self.prev_line(pos) {
+            used += self.line_rows(prev);
+            if used > rows {
+                break;
             }
-        } else {
-            false
+            pos = prev;
         }
+
+        self.set_top(pos)
     }
 
-    fn scroll_down(&mut self) -> bool {
-        let newline_pos = self.mmap[self.scroll_pos..]
-            .iter()
-            .position(|c| *c == b'\n');
-        if let Some(index) = newline_pos {
-            let new_scroll_pos = self.scroll_pos + index + 1;
-            if new_scroll_pos >= self.mmap.len() {
-                false
-            } else {
-                self.scroll_pos = new_scroll_pos;
-                self.cursor = new_scroll_pos;
-                true
+    /// Show line `line` (starting at 1) at the top, or the last line if the file is shorter
+    fn jump_to_line(&mut self, line: usize) -> bool {
+        let mut pos = 0;
+        for _ in 1..line {
+            match self.next_line(pos) {
+                Some(next) => pos = next,
+                None => break,
             }
-        } else {
-            false
         }
+
+        self.set_top(pos)
     }
 
-    fn scroll_up(&mut self) -> bool {
-        if self.scroll_pos <= 1 {
+    /// Show the line at `percent` of the file at the top
+    fn jump_to_percent(&mut self, percent: usize) -> bool {
+        if !self.load_all() {
+            self.notice = Some("Interrupted".into());
+            return false;
+        }
+        let len = self.store.data().len();
+        if len == 0 {
             return false;
         }
 
-        let newline_pos = self.mmap[..(self.scroll_pos - 1)]
-            .iter()
-            .rposition(|c| *c == b'\n');
-        if let Some(index) = newline_pos {
-            let new_scroll_pos = index + 1;
-            self.scroll_pos = new_scroll_pos;
-            self.cursor = new_scroll_pos;
-        } else {
-            self.scroll_pos = 0;
-            self.cursor = 0;
+        let target = (len as u128 * percent.min(100) as u128 / 100) as usize;
+        let pos = self.line_start(target.min(len - 1));
+
+        self.set_top(pos)
+    }
+
+    fn scroll_down(&mut self, lines: usize) -> bool {
+        let mut pos = self.scroll_pos;
+        for _ in 0..lines {
+            match self.next_line(pos) {
+                Some(next) => pos = next,
+                None => break,
+            }
         }
 
-        true
+        self.set_top(pos)
+    }
+
+    fn scroll_up(&mut self, lines: usize) -> bool {
+        let mut pos = self.scroll_pos;
+        for _ in 0..lines {
+            match self.prev_line(pos) {
+                Some(prev) => pos = prev,
+                None => break,
+            }
+        }
+
+        self.set_top(pos)
+    }
+
+    /// Scroll forward by `rows` terminal rows, a partially shown line becomes the top line
+    fn page_down(&mut self, rows: usize) -> bool {
+        let mut pos = self.scroll_pos;
+        let mut used = 0;
+        while let Some(next) = self.next_line(pos) {
+            used += self.line_rows(pos);
+            if used > rows && pos != self.scroll_pos {
+                break;
+            }
+            pos = next;
+            if used >= rows {
+                break;
+            }
+        }
+
+        self.set_top(pos)
     }
 
-    fn search(&mut self, search: &str) -> bool {
+    /// Scroll backward by `rows` terminal rows, keeping at least one line of the current screen
+    fn page_up(&mut self, rows: usize) -> bool {
+        let mut pos = self.scroll_pos;
+        let mut used = 0;
+        while let Some(prev) = self.prev_line(pos) {
+            used += self.line_rows(prev);
+            if used > rows && pos != self.scroll_pos {
+                break;
+            }
+            pos = prev;
+            if used >= rows {
+                break;
+            }
+        }
+
+        self.set_top(pos)
+    }
+
+    /// Search from the top line the pattern was started at. Incremental searches update the
+    /// pattern while it is typed, the last one completes it.
+    fn search(&mut self, direction: Direction, search: &str, incremental: bool) -> bool {
+        let origin = *self.search_origin.get_or_insert(self.scroll_pos);
+        if !incremental {
+            self.search_origin = None;
+        }
+
         self.search = Regex::new(search).ok();
-        self.cursor = self.scroll_pos;
-        self.search_next();
+        self.direction = direction;
+        if self.search.is_none() {
+            self.notice = Some("Invalid pattern".into());
+            return true;
+        }
+
+        // searching forward includes the top line, searching backward starts above it
+        self.search_from(direction, origin);
 
         true // always redraw since the search query may change
     }
 
+    /// Abandon the pattern being typed, moving back to where it was started
+    fn cancel_search(&mut self) -> bool {
+        self.search = None;
+        if let Some(origin) = self.search_origin.take() {
+            self.set_top(origin);
+        }
+
+        true // redraw without highlights
+    }
+
     fn search_next(&mut self) -> bool {
+        self.search_again(self.direction)
+    }
+
+    fn search_prev(&mut self) -> bool {
+        self.search_again(self.direction.reverse())
+    }
+
+    /// Move to the next match in `direction`, skipping the match at the cursor
+    fn search_again(&mut self, direction: Direction) -> bool {
+        match direction {
+            Direction::Forward => self.search_from(direction, self.cursor + 1),
+            Direction::Backward => self.search_from(direction, self.cursor),
+        }
+    }
+
+    /// Move to the next match starting at or after `pos`, or the previous match starting before
+    /// `pos`, wrapping around at the end of the file. Content is only loaded up to the match,
+    /// the search wraps around once all content is loaded.
+    fn search_from(&mut self, direction: Direction, pos: usize) -> bool {
         let regex = match &self.search {
             None => return false,
-            Some(regex) => regex,
+            Some(regex) => regex.clone(),
         };
 
-        if let Some(mat) = regex.find(&self.mmap[(self.cursor + 1)..]) {
-            let new_pos = self.cursor + mat.start() + 1;
-            self.scroll_pos = new_pos;
-            self.scroll_up();
-            self.cursor = new_pos;
-            true
-        } else {
-            false
+        let (found, wrapped) = match direction {
+            Direction::Forward => match self.find_forward(&regex, pos) {
+                Some(found) => (Some(found), false),
+                None if self.interrupted() => (None, false),
+                None => (self.find_forward(&regex, 0), true),
+            },
+            Direction::Backward => match self.find_backward(&regex, pos) {
+                Some(found) => (Some(found), false),
+                None if !self.load_all() => (None, false),
+                None => {
+                    let len = self.store.data().len();
+                    (self.find_backward(&regex, len), true)
+                }
+            },
+        };
+
+        match found {
+            None if self.interrupted() => {
+                self.notice = Some("Search interrupted".into());
+                false
+            }
+            None => {
+                self.notice = Some("Pattern not found".into());
+                false
+            }
+            Some(found) => {
+                if wrapped {
+                    self.notice = Some(
+                        match direction {
+                            Direction::Forward => "search hit BOTTOM, continuing at TOP",
+                            Direction::Backward => "search hit TOP, continuing at BOTTOM",
+                        }
+                        .into(),
+                    );
+                }
+                self.scroll_pos = self.line_start(found);
+                self.cursor = found;
+                true
+            }
         }
     }
 
-    fn search_prev(&mut self) -> bool {
-        let _regex = match &self.search {
-            None => return false,
-            Some(regex) => regex,
-        };
+    /// Start of the first match starting at or after `pos`, loading content until there is a
+    /// match. Until the content is complete only complete lines are searched, so a match is not
+    /// cut off by the end of the content loaded so far.
+    fn find_forward(&mut self, regex: &Regex, pos: usize) -> Option<usize> {
+        let mut from = pos.min(self.store.data().len());
+        loop {
+            let complete = self.store.complete();
+            let data = self.store.data();
+            let limit = if complete {
+                data.len()
+            } else {
+                data[from..]
+                    .iter()
+                    .rposition(|c| *c == b'\n')
+                    .map_or(from, |index| from + index + 1)
+            };
 
-        todo!()
+            if let Some(mat) = regex.find(&data[from..limit]) {
+                return Some(from + mat.start());
+            }
+            if complete {
+                return None;
+            }
+
+            from = limit;
+            // at the end of the content the remainder is searched once more
+            if !self.load_more() && self.interrupted() {
+                return None;
+            }
+        }
+    }
+
+    /// Start of the last match starting before `pos`
+    ///
+    /// The regex cannot run in reverse, so the file is scanned backwards in windows of
+    /// increasing size. Windows start at a line start and end at the end of the line that
+    /// contains the end of the window, so every region is only searched once.
+    fn find_backward(&self, regex: &Regex, pos: usize) -> Option<usize> {
+        let data = self.store.data();
+        let mut end = pos.min(data.len());
+        let mut window = SEARCH_WINDOW;
+        while end > 0 {
+            let start = self.line_start(end.saturating_sub(window));
+            let limit = data[end..]
+                .iter()
+                .position(|c| *c == b'\n')
+                .map(|index| end + index)
+                .unwrap_or(data.len());
+
+            let found = regex
+                .find_iter(&data[start..limit])
+                .map(|mat| start + mat.start())
+                .take_while(|found| *found < end)
+                .last();
+            if found.is_some() {
+                return found;
+            }
+
+            end = start;
+            window = window.saturating_mul(2);
+        }
+
+        None
+    }
+
+    /// Load the content for the screen that has been read already, returns whether anything
+    /// was loaded
+    fn fill(&mut self) -> bool {
+        let width = self.size.0.max(1) as usize;
+        // a row holds at most `width` bytes and a newline
+        let len = self.scroll_pos + self.rows() * (width + 1);
+
+        self.store.load_available(len)
     }
 
     fn draw_onto(
-        &self,
+        &mut self,
         stdout: &mut AlternateScreen<RawTerminal<std::io::Stdout>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fill();
         write!(stdout, "{}", clear::All)?;
 
-        let chunk_size = self.size.0 as usize;
-        let height = self.size.1 as usize;
+        let chunk_size = self.size.0.max(1) as usize;
+        let height = self.rows();
 
-        self.mmap[self.scroll_pos..]
+        self.store.data()[self.scroll_pos..]
             .split(|c| *c == b'\n')
             .flat_map(move |cs| {
                 if cs.is_empty() {
@@ -235,20 +715,32 @@ impl Pager {
 
 enum ReadlineState {
     Initial,
-    Slash(String),
-    Number(i64),
+    Pattern(Direction, String),
+    /// count prefix for the next command
+    Number(usize),
 }
+
 enum Action {
     Status,
     Exit,
     JumpToTop,
     JumpToBottom,
-    NextLine,
-    PrevLine,
-    Search(String),
+    JumpToLine(usize),
+    JumpToPercent(usize),
+    NextLine(usize),
+    PrevLine(usize),
+    /// scroll a page, or the given number of rows
+    PageDown(Option<usize>),
+    PageUp(Option<usize>),
+    /// scroll half a page, or the given number of rows
+    HalfPageDown(Option<usize>),
+    HalfPageUp(Option<usize>),
+    Search(Direction, String),
+    /// search while the pattern is typed
+    SearchIncremental(Direction, String),
+    SearchCancel,
     SearchNext,
     SearchPrev,
-    Jump(i64),
 }
 
 use std::fmt;
@@ -256,8 +748,9 @@ impl fmt::Display for ReadlineState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ReadlineState::Initial => write!(f, ":"),
-            ReadlineState::Slash(s) => write!(f, "search:{}", s),
-            ReadlineState::Number(i) => write!(f, "jump:{}", i),
+            ReadlineState::Pattern(Direction::Forward, s) => write!(f, "search:{}", s),
+            ReadlineState::Pattern(Direction::Backward, s) => write!(f, "search backward:{}", s),
+            ReadlineState::Number(i) => write!(f, ":{}", i),
         }
     }
 }
@@ -266,46 +759,38 @@ impl ReadlineState {
     fn next(&mut self, key: Key) -> Option<Action> {
         match self {
             ReadlineState::Initial => match key {
-                Key::Char('G') => Some(Action::JumpToBottom),
-                Key::Char('N') => Some(Action::SearchPrev),
-                Key::Char('g') => Some(Action::JumpToTop),
-                Key::Char('j') => Some(Action::NextLine),
-                Key::Char('k') => Some(Action::PrevLine),
-                Key::Char('n') => Some(Action::SearchNext),
-                Key::Char('q') => Some(Action::Exit),
-                Key::Ctrl('g') => Some(Action::Status),
-
-                Key::Down => Some(Action::NextLine),
-                Key::Up => Some(Action::PrevLine),
-
                 Key::Char('/') => {
-                    *self = ReadlineState::Slash(String::new());
+                    *self = ReadlineState::Pattern(Direction::Forward, String::new());
+                    None
+                }
+                Key::Char('?') => {
+                    *self = ReadlineState::Pattern(Direction::Backward, String::new());
                     None
                 }
                 Key::Char(c) if c > '0' && c <= '9' => {
-                    *self = ReadlineState::Number(c.to_digit(10).unwrap() as i64);
+                    *self = ReadlineState::Number(c.to_digit(10).unwrap() as usize);
                     None
                 }
-                _ => None,
+                key => Self::command(key, None),
             },
-            ReadlineState::Slash(s) => match key {
-                Key::Esc => {
+            ReadlineState::Pattern(direction, s) => match key {
+                Key::Esc | Key::Ctrl('c') => {
                     *self = ReadlineState::Initial;
-                    None
+                    Some(Action::SearchCancel)
                 }
                 Key::Backspace => {
                     s.pop();
                     if s.is_empty() {
                         *self = ReadlineState::Initial;
-                        None
+                        Some(Action::SearchCancel)
                     } else {
-                        Some(Action::Search(s.clone()))
+                        Some(Action::SearchIncremental(*direction, s.clone()))
                     }
                 }
                 Key::Char('\n') => {
-                    let search = s.clone();
+                    let action = Action::Search(*direction, s.clone());
                     *self = ReadlineState::Initial;
-                    Some(Action::Search(search))
+                    Some(action)
                 }
                 Key::Char(c) => {
                     s.push(c);
@@ -313,11 +798,9 @@ impl ReadlineState {
                     if s.chars().filter(|c| *c != '.').count() == 0 {
                         None // do not search this pattern
                     } else {
-                        Some(Action::Search(s.clone()))
+                        Some(Action::SearchIncremental(*direction, s.clone()))
                     }
                 }
-                Key::Down => todo!(),
-                Key::Up => todo!(),
                 _ => None,
             },
             ReadlineState::Number(i) => match key {
@@ -325,20 +808,59 @@ impl ReadlineState {
                     *self = ReadlineState::Initial;
                     None
                 }
-                Key::Char('\n') => {
-                    let jump = *i;
-                    *self = ReadlineState::Initial;
-                    Some(Action::Jump(jump))
+                Key::Backspace => {
+                    *i /= 10;
+                    if *i == 0 {
+                        *self = ReadlineState::Initial;
+                    }
+                    None
                 }
-                Key::Char(c) if c > '0' && c <= '9' => {
-                    let new = 10 * *i + c.to_digit(10).unwrap() as i64;
-                    *self = ReadlineState::Number(new);
+                Key::Char(c) if c.is_ascii_digit() => {
+                    *i = i
+                        .saturating_mul(10)
+                        .saturating_add(c.to_digit(10).unwrap() as usize);
                     None
                 }
-                _ => None,
+                key => {
+                    let count = *i;
+                    *self = ReadlineState::Initial;
+                    Self::command(key, Some(count))
+                }
             },
         }
     }
+
+    /// Action for a command key, with an optional count prefix
+    fn command(key: Key, count: Option<usize>) -> Option<Action> {
+        match key {
+            Key::Char('G') | Key::Char('>') | Key::End => {
+                Some(count.map_or(Action::JumpToBottom, Action::JumpToLine))
+            }
+            Key::Char('g') | Key::Char('<') | Key::Home => {
+                Some(count.map_or(Action::JumpToTop, Action::JumpToLine))
+            }
+            Key::Char('%') | Key::Char('p') => Some(Action::JumpToPercent(count.unwrap_or(0))),
+
+            Key::Char('j') | Key::Char('e') | Key::Char('\n') | Key::Down | Key::Ctrl('n') => {
+                Some(Action::NextLine(count.unwrap_or(1)))
+            }
+            Key::Char('k') | Key::Char('y') | Key::Up | Key::Ctrl('p') => {
+                Some(Action::PrevLine(count.unwrap_or(1)))
+            }
+            Key::Char(' ') | Key::Char('f') | Key::PageDown | Key::Ctrl('f') => {
+                Some(Action::PageDown(count))
+            }
+            Key::Char('b') | Key::PageUp | Key::Ctrl('b') => Some(Action::PageUp(count)),
+            Key::Char('d') | Key::Ctrl('d') => Some(Action::HalfPageDown(count)),
+            Key::Char('u') | Key::Ctrl('u') => Some(Action::HalfPageUp(count)),
+
+            Key::Char('N') => Some(Action::SearchPrev),
+            Key::Char('n') => Some(Action::SearchNext),
+            Key::Char('q') => Some(Action::Exit),
+            Key::Ctrl('g') => Some(Action::Status),
+            _ => None,
+        }
+    }
 }
 
 /// Parse arguments, run job, pass return code
@@ -346,13 +868,15 @@ fn main() -> ! {
     let mut args = std::env::args();
     args.next(); // bin name
 
-    let filename = match args.next() {
+    let input = match args.next() {
+        Some(s) if s == "-" => InputArg::Stdin,
         Some(s) if s.starts_with('-') => print_help_and_exit(USAGE),
-        Some(s) => s,
-        None => print_help_and_exit(USAGE),
+        Some(s) => InputArg::File(s),
+        None if is_tty(&stdin()) => print_help_and_exit(USAGE),
+        None => InputArg::Stdin,
     };
 
-    match less(&filename) {
+    match less(&input) {
         Ok(_) => exit(0),
         Err(e) => {
             eprintln!("{:?}", e);
@@ -362,49 +886,335 @@ fn main() -> ! {
 }
 
 /// `less` implementation
-fn less(filename: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::open(filename)?;
-    let mmap = unsafe { MmapOptions::new().map(&file)? };
+fn less(input: &InputArg<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let name = match input {
+        InputArg::Stdin => "standard input",
+        InputArg::File(filename) => filename,
+    };
+
+    // block SIGWINCH before spawning threads, which inherit the mask, so only the thread
+    // waiting for it receives it
+    let mut winch = SigSet::empty();
+    winch.add(Signal::SIGWINCH);
+    winch.thread_block()?;
+
+    let (events, receiver) = channel();
+    let store = Store::open(Input::try_from(input)?, events.clone())?;
     let size = terminal_size()?;
 
-    let mut pager = Pager::new(mmap, size);
+    let mut pager = Pager::new(store, size);
+    let keys_pending = pager.keys_pending.clone();
+
+    let resizes = events.clone();
+    thread::spawn(move || {
+        while winch.wait().is_ok() {
+            if resizes.send(Event::Resize).is_err() {
+                break;
+            }
+        }
+    });
+
+    // stdin may be the content, read keys from the terminal
+    let tty = get_tty()?;
+    thread::spawn(move || {
+        for key in tty.keys() {
+            // counted before it is sent, so the pager sees it while it is waiting
+            keys_pending.fetch_add(1, Ordering::SeqCst);
+            if events.send(Event::Key(key)).is_err() {
+                break;
+            }
+        }
+    });
 
     let mut stdout = AlternateScreen::from(stdout().into_raw_mode()?);
     write!(stdout, "{}", cursor::Hide)?;
 
-    let stdin = stdin();
-    let stdin = stdin.lock();
-
     let mut readline = ReadlineState::Initial;
+    let mut status = name.to_string();
     pager.draw_onto(&mut stdout)?;
-    pager.draw_status(&mut stdout, filename)?;
-
-    for c in stdin.keys() {
-        let c = c?;
-        let action = readline.next(c);
-        if let Some(action) = action {
-            let redraw = match action {
-                Action::Exit => break,
-                Action::Status => true, // todo
-                Action::JumpToTop => pager.jump_to_top(),
-                Action::JumpToBottom => pager.jump_to_bottom(),
-                Action::NextLine => pager.scroll_down(),
-                Action::PrevLine => pager.scroll_up(),
-                Action::SearchNext => pager.search_next(),
-                Action::SearchPrev => pager.search_prev(),
-                Action::Search(s) => pager.search(&s),
-                Action::Jump(_s) => true, // todo
-            };
-            if redraw {
+    pager.draw_status(&mut stdout, &status)?;
+
+    for event in receiver {
+        match event {
+            Event::Key(key) => {
+                pager.keys_pending.fetch_sub(1, Ordering::SeqCst);
+                if let Some(action) = readline.next(key?) {
+                    let redraw = match action {
+                        Action::Exit => break,
+                        Action::Status => true, // todo
+                        Action::JumpToTop => pager.jump_to_top(),
+                        Action::JumpToBottom => pager.jump_to_bottom(),
+                        Action::JumpToLine(line) => pager.jump_to_line(line),
+                        Action::JumpToPercent(percent) => pager.jump_to_percent(percent),
+                        Action::NextLine(lines) => pager.scroll_down(lines),
+                        Action::PrevLine(lines) => pager.scroll_up(lines),
+                        Action::PageDown(rows) => {
+                            pager.page_down(rows.unwrap_or_else(|| pager.rows()))
+                        }
+                        Action::PageUp(rows) => pager.page_up(rows.unwrap_or_else(|| pager.rows())),
+                        Action::HalfPageDown(rows) => {
+                            pager.page_down(rows.unwrap_or_else(|| (pager.rows() / 2).max(1)))
+                        }
+                        Action::HalfPageUp(rows) => {
+                            pager.page_up(rows.unwrap_or_else(|| (pager.rows() / 2).max(1)))
+                        }
+                        Action::SearchNext => pager.search_next(),
+                        Action::SearchPrev => pager.search_prev(),
+                        Action::Search(direction, s) => pager.search(direction, &s, false),
+                        Action::SearchIncremental(direction, s) => {
+                            pager.search(direction, &s, true)
+                        }
+                        Action::SearchCancel => pager.cancel_search(),
+                    };
+                    if redraw {
+                        pager.draw_onto(&mut stdout)?;
+                    } else if pager.notice.is_none() {
+                        emit_bell();
+                    }
+                }
+                status = readline.to_string();
+            }
+            Event::Data => {
+                // only redraw when the screen was waiting for this content
+                if !pager.fill() {
+                    continue;
+                }
+                pager.draw_onto(&mut stdout)?;
+            }
+            Event::Resize => {
+                pager.resize(terminal_size()?);
                 pager.draw_onto(&mut stdout)?;
-            } else {
-                emit_bell();
             }
         }
-        pager.draw_status(&mut stdout, &format!("{}", readline))?;
+
+        if let Some(e) = pager.store.take_error() {
+            pager.notice = Some(format!("read error: {}", e));
+        }
+
+        let line = match pager.notice.take() {
+            Some(notice) if matches!(readline, ReadlineState::Initial) => notice,
+            Some(notice) => format!("{} ({})", status, notice),
+            None => status.clone(),
+        };
+        pager.draw_status(&mut stdout, &line)?;
     }
 
     write!(stdout, "{}", cursor::Show)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pager over `content` on a terminal of `width` by `height`, the last row shows the status
+    fn pager(content: &str, width: u16, height: u16) -> Pager {
+        let (_, chunks) = sync_channel(0);
+        let stream = Stream {
+            data: content.as_bytes().to_vec(),
+            chunks,
+            done: true,
+            error: None,
+        };
+        Pager::new(Store::Streamed(stream), (width, height))
+    }
+
+    /// Lines with the numbers 1 to `n`
+    fn numbers(n: usize) -> String {
+        (1..=n).map(|i| format!("{}\n", i)).collect()
+    }
+
+    /// The top line shown
+    fn top(pager: &Pager) -> String {
+        let data = &pager.store.data()[pager.scroll_pos..];
+        let line = data.split(|c| *c == b'\n').next().unwrap();
+        String::from_utf8_lossy(line).into_owned()
+    }
+
+    #[test]
+    fn test_page() {
+        let mut pager = pager(&numbers(10), 80, 5);
+        assert_eq!(pager.rows(), 4);
+
+        assert!(pager.page_down(4));
+        assert_eq!(top(&pager), "5");
+        assert!(pager.page_down(4));
+        assert_eq!(top(&pager), "9");
+        assert!(pager.page_up(2));
+        assert_eq!(top(&pager), "7");
+        assert!(pager.page_up(4));
+        assert_eq!(top(&pager), "3");
+        assert!(pager.page_up(4));
+        assert_eq!(top(&pager), "1");
+        assert!(!pager.page_up(4));
+    }
+
+    #[test]
+    fn test_page_wrapped() {
+        // at a width of 4 the line of b's takes 3 rows and the line of c's 4 rows
+        let content = "a\nbbbbbbbbbb\ncccccccccccccc\nd\ne\n";
+        let mut pager = pager(content, 4, 5);
+
+        assert!(pager.page_down(4));
+        assert_eq!(top(&pager), "cccccccccccccc");
+        // a line longer than the screen still moves
+        assert!(pager.page_down(3));
+        assert_eq!(top(&pager), "d");
+
+        assert!(pager.page_up(4));
+        assert_eq!(top(&pager), "cccccccccccccc");
+        assert!(pager.page_up(2));
+        assert_eq!(top(&pager), "bbbbbbbbbb");
+        assert!(pager.page_up(4));
+        assert_eq!(top(&pager), "a");
+    }
+
+    #[test]
+    fn test_page_partial() {
+        // at a width of 4 the line of c's takes 3 rows, the screen ends halfway through it
+        let content = "a\nb\ncccccccccc\nd\ne\n";
+        let mut pager = pager(content, 4, 5);
+
+        assert!(pager.page_down(4));
+        assert_eq!(top(&pager), "cccccccccc");
+
+        assert!(pager.page_up(1));
+        assert_eq!(top(&pager), "b");
+        assert!(pager.page_up(4));
+        assert_eq!(top(&pager), "a");
+    }
+
+    #[test]
+    fn test_beyond_end() {
+        let mut pager = pager(&numbers(10), 80, 5);
+
+        assert!(pager.page_down(100));
+        assert_eq!(top(&pager), "10");
+        assert!(!pager.scroll_down(1));
+        assert!(pager.scroll_up(100));
+        assert_eq!(top(&pager), "1");
+        assert!(pager.scroll_down(100));
+        assert_eq!(top(&pager), "10");
+
+        assert!(pager.jump_to_line(3));
+        assert_eq!(top(&pager), "3");
+        assert!(pager.jump_to_line(100));
+        assert_eq!(top(&pager), "10");
+        assert!(pager.jump_to_line(0));
+        assert_eq!(top(&pager), "1");
+
+        assert!(pager.jump_to_bottom());
+        assert_eq!(top(&pager), "7");
+    }
+
+    #[test]
+    fn test_jump_to_percent() {
+        let mut pager = pager(&numbers(10), 80, 5);
+
+        // line 6 starts at byte 10 of 21
+        assert!(pager.jump_to_percent(50));
+        assert_eq!(top(&pager), "6");
+        assert!(pager.jump_to_percent(100));
+        assert_eq!(top(&pager), "10");
+        assert!(!pager.jump_to_percent(200));
+        assert!(pager.jump_to_percent(0));
+        assert_eq!(top(&pager), "1");
+
+        let mut pager = self::pager("", 80, 5);
+        assert!(!pager.jump_to_percent(50));
+        assert!(!pager.jump_to_bottom());
+    }
+
+    fn regex(pattern: &str) -> Regex {
+        Regex::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_find_backward() {
+        let mut pager = pager("ab ab\nc\nab\n", 80, 5);
+        let ab = regex("ab");
+
+        assert_eq!(pager.find_backward(&ab, 12), Some(8));
+        // the last match on a line before `pos` wins
+        assert_eq!(pager.find_backward(&ab, 8), Some(3));
+        assert_eq!(pager.find_backward(&ab, 4), Some(3));
+        assert_eq!(pager.find_backward(&ab, 3), Some(0));
+        assert_eq!(pager.find_backward(&ab, 0), None);
+        assert_eq!(pager.find_backward(&regex("d"), 12), None);
+
+        assert_eq!(pager.find_forward(&ab, 1), Some(3));
+        assert_eq!(pager.find_forward(&ab, 9), None);
+    }
+
+    #[test]
+    fn test_find_backward_windows() {
+        // the matches are several windows apart, the last one at the end of a long line
+        let filler = "x".repeat(99) + "\n";
+        let mut content = String::from("needle\n");
+        content += &filler.repeat(SEARCH_WINDOW * 3 / filler.len());
+        let second = content.len();
+        content += "needle\n";
+        let third = SEARCH_WINDOW * 5 - 3;
+        content += &"x".repeat(third - content.len());
+        content += "needle\n";
+        content += &filler.repeat(SEARCH_WINDOW / filler.len());
+
+        let pager = pager(&content, 80, 5);
+        let needle = regex("needle");
+        assert_eq!(pager.find_backward(&needle, content.len()), Some(third));
+        assert_eq!(pager.find_backward(&needle, third), Some(second));
+        assert_eq!(pager.find_backward(&needle, second), Some(0));
+        assert_eq!(pager.find_backward(&needle, 0), None);
+    }
+
+    #[test]
+    fn test_search_wraps() {
+        let mut pager = pager("ab\nc\nab\nd\n", 80, 3);
+        pager.search = Some(regex("ab"));
+
+        assert!(pager.search_from(Direction::Backward, 5));
+        assert_eq!((pager.cursor, pager.notice.take()), (0, None));
+        assert!(pager.search_from(Direction::Backward, 0));
+        assert_eq!(pager.cursor, 5);
+        assert_eq!(pager.scroll_pos, 5);
+        let notice = pager.notice.take();
+        assert_eq!(
+            notice.as_deref(),
+            Some("search hit TOP, continuing at BOTTOM")
+        );
+
+        assert!(pager.search_from(Direction::Forward, 6));
+        assert_eq!(pager.cursor, 0);
+        let notice = pager.notice.take();
+        assert_eq!(
+            notice.as_deref(),
+            Some("search hit BOTTOM, continuing at TOP")
+        );
+
+        pager.search = Some(regex("e"));
+        assert!(!pager.search_from(Direction::Backward, 5));
+        assert_eq!(pager.notice.as_deref(), Some("Pattern not found"));
+        assert_eq!(pager.cursor, 0);
+    }
+
+    #[test]
+    fn test_find_forward_stream() {
+        // a match is not cut off by the end of the content loaded so far
+        let (sender, chunks) = sync_channel(READ_AHEAD);
+        let stream = Stream {
+            data: b"ab\nxx ne".to_vec(),
+            chunks,
+            done: false,
+            error: None,
+        };
+        let mut pager = Pager::new(Store::Streamed(stream), (80, 5));
+        sender.send(Ok(b"edle\nneedle".to_vec())).unwrap();
+        drop(sender);
+
+        let needle = regex("needle");
+        assert_eq!(pager.find_forward(&needle, 0), Some(6));
+        assert_eq!(pager.find_forward(&needle, 7), Some(13));
+        assert!(pager.store.complete());
+    }
+}
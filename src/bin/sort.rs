@@ -2,512 +2,3545 @@
 //!
 //! With no FILE, or when FILE is -, read standard input.
 //!
+//! KEYDEF is `F[.C][OPTS][,F[.C][OPTS]]` for start and stop position, where F is a field number
+//! and C a character position in the field; both are origin 1, and the stop position defaults to
+//! the line's end. If neither -t nor -b is in effect, characters in a field are counted from the
+//! beginning of the preceding whitespace. OPTS is one or more single-letter ordering options
+//! [bdfghiMnRrV], which override global ordering options for that key. If no key is given, use the
+//! entire line as the key.
+//!
+//! Text is compared by byte value, unless the environment (`LC_ALL`, `LC_COLLATE` or `LANG`)
+//! selects a locale other than C or POSIX. Locale collation is approximated by comparing
+//! alphanumeric characters case-insensitively first, then by case, then by byte value.
+//!
+//! With `--check`, the input is only verified to be sorted. The first out of order line is
+//! reported and the exit status is 1.
+//!
+//! With `--merge`, already sorted inputs are merged without sorting. Inputs are merged at most
+//! NMERGE (`--batch-size`) at a time, using temp files for multiple passes.
+//!
+//! The output (`-o FILE`) may be one of the inputs, also through a hard link. In that case the
+//! result is written to a temp file in the same directory, which atomically replaces the output
+//! when done. A symlinked output replaces the file it points to.
+//!
+//! Equal lines are kept in input order when the last-resort comparison is disabled (stable or
+//! unique sort), also when they end up in different chunks of an external sort.
+//!
+//! The memory limit (`-S`) accounts for the allocation overhead of every line. When sorting gets
+//! close to it, all chunks in memory are merged into temp files before reading on.
+//!
+//! Temp files are created in the `-T` directories in turn, or in `$TMPDIR`. With
+//! `--compress-program`, they are compressed by piping through PROG, and read back through
+//! `PROG -d`.
+//!
+//! With `-z`, lines are delimited by NUL instead of newline, in the inputs, the output and the
+//! temp files used for external sorts.
+//!
+//! With `-R`, lines are shuffled by comparing a seeded hash of their keys, so equal keys end up
+//! next to each other. The seed is taken from `--random-source` for reproducible output.
+//!
+//! Regular files that fit in the buffer (`-S`, unlimited by default) are mapped into memory, and
+//! slices of the mapping are sorted instead of copies of every line. Inputs are read instead when
+//! the output is one of them.
+//!
+//! The final merge of chunks in memory runs in parallel, on key ranges split by sampled lines.
+//!
+//! Failing to read an input, or to write the output or a temp file, stops the sort with exit
+//! status 2. Jobs still running are finished first, so no temp files are left behind.
+//!
+//! `--debug` underlines the part of each output line used by every key, and `--stats` reports
+//! chunks sorted, merges, bytes spilled to temp files, peak memory and wall time per phase on
+//! stderr when done.
+//!
 //! Todo:
 //!  - code cleanup
-//!  - more sort ordering
-//!  - reverse
-//!  - unique
 
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
 use std::collections::BinaryHeap;
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroU32, NonZeroUsize};
 use std::ops::DerefMut;
-use std::process::exit;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use std::io::Split;
 use std::vec::IntoIter;
 
 use coreutils::executor::{Job, ThreadPool};
-use coreutils::io::{Input, InputArg};
+use coreutils::io::{Input, InputArg, Output, OutputArg};
 
 use clap::{App, Arg};
-use tempfile::tempfile;
+use memmap::{Mmap, MmapOptions};
+use tempfile::{tempfile_in, NamedTempFile};
 
 const USAGE: &str = "Sort lines of text files";
 const N_WAY_MERGE: usize = 5;
+/// Sort at most this many lines per chunk
+const CHUNK_LINES: usize = 100_000;
+/// Default number of inputs to merge at once
+const DEFAULT_BATCH_SIZE: usize = 16;
+/// Lines sampled per partition of a parallel merge, to find the splitters
+const SAMPLES_PER_PARTITION: usize = 32;
+/// Smallest allocation of the system allocator, and its granularity
+const MIN_ALLOCATION: usize = 32;
 
 type Line = Box<[u8]>;
 
+/// Memory held by a line: the boxed slice in its chunk and the heap allocation behind it
+fn line_cost(line: &[u8]) -> usize {
+    let allocation = match line.len() {
+        0 => 0, // empty boxes do not allocate
+        // payload plus malloc header, rounded up to the allocation granularity
+        len => ((len + 8 + 15) & !15).max(MIN_ALLOCATION),
+    };
+
+    std::mem::size_of::<Line>() + allocation
+}
+
 /// `sort` payload
-#[allow(dead_code)]
 struct Payload<'a> {
     /// sort these inputs
     inputs: Vec<InputArg<&'a str>>,
-    /// sort order
-    sort_order: SortOrder,
+    /// write result to this output
+    output: OutputArg<&'a str>,
+    /// sort or only check the input
+    mode: Mode,
+    /// annotate the part of each line used for sorting
+    debug: bool,
+    /// line comparison, by keys
+    comparator: Comparator,
     /// merge at most N inputs at once
     batch_size: NonZeroUsize,
     /// main memory buffer size
     buffer_size: Option<NonZeroUsize>,
     /// number of sorts run concurrently
     sort_threads: NonZeroU32,
+    /// line delimiter, newline or NUL
+    delimiter: u8,
+    /// directories for temp files
+    temp_dirs: Vec<PathBuf>,
+    /// compress temp files with this program
+    compress_program: Option<String>,
+}
+
+/// Failure of a run, reported by `main`
+#[derive(Debug)]
+enum SortError {
+    /// an input or temp file could not be opened or read
+    Read(String, io::Error),
+    /// the output could not be opened
+    Open(String, io::Error),
+    /// a temp file could not be written
+    Write(String, io::Error),
+    /// the output could not be written
+    Output(String, io::Error),
+    /// no temp file could be created in this directory
+    TempFile(PathBuf, io::Error),
+    /// the compress program could not be started
+    Compress(String, io::Error),
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortError::Read(name, e) => write!(f, "cannot read: {}: {}", name, e),
+            SortError::Open(name, e) => write!(f, "open failed: {}: {}", name, e),
+            SortError::Write(name, e) | SortError::Output(name, e) => {
+                write!(f, "write failed: {}: {}", name, e)
+            }
+            SortError::TempFile(dir, e) => write!(
+                f,
+                "cannot create temporary file in '{}': {}",
+                dir.display(),
+                e
+            ),
+            SortError::Compress(program, e) => {
+                write!(f, "couldn't create process for {}: {}", program, e)
+            }
+        }
+    }
+}
+
+impl SortError {
+    /// The reader of the output went away, e.g. `sort | head`, nothing left to report
+    fn is_output_closed(&self) -> bool {
+        matches!(self, SortError::Output(_, e) if e.kind() == io::ErrorKind::BrokenPipe)
+    }
 }
 
-#[allow(dead_code)]
+/// Name of temp files in messages
+const TEMP_FILE: &str = "temporary file";
+
+/// What to do with the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// sort lines
+    Sort,
+    /// check for sorted input, report the first disorder unless `quiet`
+    Check { quiet: bool },
+    /// merge already sorted inputs
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 enum SortOrder {
+    /// text, by locale collation
     Locale,
+    /// text, by byte value
+    #[default]
     Bytes,
+    /// string numerical value, `-n`
     Numerical,
-    CaseInsensitive,
-    // ...
+    /// general numerical value, `-g`
+    General,
+    /// human readable numbers, e.g. 2K 1G, `-h`
+    Human,
+    /// month names, `-M`
+    Month,
+    /// natural sort of (version) numbers within text, `-V`
+    Version,
+    /// random order by a hash of the key with this seed, equal keys are grouped together, `-R`
+    Random(u64),
 }
 
-#[derive(Debug)]
-enum LineIterator {
-    File(Split<BufReader<File>>),
-    Vec(IntoIter<Line>),
-}
-impl Iterator for LineIterator {
-    type Item = Line;
+impl SortOrder {
+    /// Does this order compare text
+    fn is_text(self) -> bool {
+        matches!(self, SortOrder::Locale | SortOrder::Bytes)
+    }
 
-    fn next(&mut self) -> Option<Line> {
+    /// Option letter, used for reporting incompatible options
+    fn letter(self) -> char {
         match self {
-            LineIterator::File(iter) => iter.next().map(|l| l.expect("I/O").into_boxed_slice()),
-            LineIterator::Vec(iter) => iter.next(),
+            SortOrder::Numerical => 'n',
+            SortOrder::General => 'g',
+            SortOrder::Human => 'h',
+            SortOrder::Month => 'M',
+            SortOrder::Version => 'V',
+            SortOrder::Random(_) => 'R',
+            SortOrder::Locale | SortOrder::Bytes => {
+                unreachable!("text is compatible with any order")
+            }
         }
     }
 }
 
-#[derive(Debug)]
-struct SortedChunk {
-    merged: bool,
-    source: LineIterator,
-    head: Option<Line>,
+/// Ordering options, global or per key
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct KeyOptions {
+    /// ignore leading blanks of the start field
+    blanks_start: bool,
+    /// ignore leading blanks of the end field
+    blanks_end: bool,
+    /// consider only blanks and alphanumeric characters
+    dictionary: bool,
+    /// consider only printable characters
+    printable: bool,
+    /// fold lower case to upper case, ignored by the numerical orders
+    fold: bool,
+    /// reverse the result of comparisons
+    reverse: bool,
+    /// how to compare the key text
+    order: SortOrder,
 }
 
-impl Iterator for SortedChunk {
-    type Item = Line;
+impl KeyOptions {
+    /// Apply single-letter ordering options, e.g. `bdr`
+    fn parse(&mut self, opts: &str, start: bool) -> Result<(), String> {
+        for c in opts.chars() {
+            match c {
+                'b' if start => self.blanks_start = true,
+                'b' => self.blanks_end = true,
+                'd' => self.dictionary = true,
+                'i' => self.printable = true,
+                'r' => self.reverse = true,
+                'f' => self.fold = true,
+                'n' => self.set_order(SortOrder::Numerical)?,
+                'g' => self.set_order(SortOrder::General)?,
+                'h' => self.set_order(SortOrder::Human)?,
+                'M' => self.set_order(SortOrder::Month)?,
+                'V' => self.set_order(SortOrder::Version)?,
+                'R' => self.set_order(SortOrder::Random(0))?,
+                _ => return Err(format!("invalid ordering option '{}'", c)),
+            }
+        }
 
-    fn next(&mut self) -> Option<Line> {
-        let next = self.source.next();
-        std::mem::replace(&mut self.head, next)
+        let random = matches!(self.order, SortOrder::Random(_));
+        if !self.order.is_text() && !random && (self.dictionary || self.printable) {
+            let letter = if self.dictionary { 'd' } else { 'i' };
+            return Err(format!(
+                "options '-{}{}' are incompatible",
+                letter,
+                self.order.letter()
+            ));
+        }
+
+        Ok(())
     }
-}
 
-impl SortedChunk {
-    pub fn new(lines: Vec<Line>) -> Self {
-        let iter = lines.into_iter();
+    /// Select a non-text order, only one of these may be active
+    fn set_order(&mut self, order: SortOrder) -> Result<(), String> {
+        if !self.order.is_text() && self.order != order {
+            return Err(format!(
+                "options '-{}{}' are incompatible",
+                self.order.letter(),
+                order.letter()
+            ));
+        }
 
-        let mut me = Self {
-            merged: false,
-            source: LineIterator::Vec(iter),
-            head: None,
-        };
+        self.order = order;
+        Ok(())
+    }
 
-        me.next();
+    /// Filter for bytes that take part in the comparison
+    fn keep(&self, c: u8) -> bool {
+        if self.dictionary && !(is_blank(c) || c.is_ascii_alphanumeric()) {
+            return false;
+        }
+        if self.printable && !(c == b' ' || c.is_ascii_graphic()) {
+            return false;
+        }
+        true
+    }
 
-        me
+    /// Are keys filtered or folded before they are compared
+    fn transforms(&self) -> bool {
+        self.dictionary || self.printable || self.fold
     }
 
-    pub fn is_flushed(&self) -> bool {
-        if let LineIterator::File(_) = self.source {
-            true
+    /// Bytes of a key that take part in the comparison, folded to upper case for `-f`
+    fn transform<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = u8> + Clone + 'a {
+        key.iter()
+            .copied()
+            .filter(move |c| self.keep(*c))
+            .map(move |c| if self.fold { c.to_ascii_uppercase() } else { c })
+    }
+
+    /// Compare two key texts
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ordering = match self.order {
+            SortOrder::Numerical => compare_numeric(a, b),
+            SortOrder::General => compare_general(a, b),
+            SortOrder::Human => compare_human(a, b),
+            SortOrder::Month => month(a).cmp(&month(b)),
+            SortOrder::Version if self.transforms() => {
+                let a: Vec<_> = self.transform(a).collect();
+                let b: Vec<_> = self.transform(b).collect();
+                compare_version(&a, &b)
+            }
+            SortOrder::Version => compare_version(a, b),
+            SortOrder::Random(seed) => {
+                let a = self.transform(a);
+                let b = self.transform(b);
+                // distinct keys with colliding hashes must not interleave
+                random_hash(seed, a.clone())
+                    .cmp(&random_hash(seed, b.clone()))
+                    .then_with(|| a.cmp(b))
+            }
+            SortOrder::Locale if self.transforms() => {
+                let a: Vec<_> = self.transform(a).collect();
+                let b: Vec<_> = self.transform(b).collect();
+                compare_locale(&a, &b)
+            }
+            SortOrder::Locale => compare_locale(a, b),
+            SortOrder::Bytes if self.transforms() => self.transform(a).cmp(self.transform(b)),
+            SortOrder::Bytes => a.cmp(b),
+        };
+
+        if self.reverse {
+            ordering.reverse()
         } else {
-            false
+            ordering
         }
     }
 
-    pub fn is_merged(&self) -> bool {
-        self.merged
+    /// Part of the key text that is actually compared, for `--debug`
+    fn span(&self, key: &[u8]) -> (usize, usize) {
+        let blanks = key.iter().take_while(|c| is_blank(**c)).count();
+
+        match self.order {
+            SortOrder::Numerical | SortOrder::Human => {
+                let (_, int, frac, rest) = parse_number(key);
+                let mut end = key.len() - rest.len();
+                let is_number = !(int.is_empty() && frac.is_empty());
+                if self.order == SortOrder::Human && is_number && unit_order(rest.first()) > 0 {
+                    end += 1;
+                }
+                (blanks, end.max(blanks))
+            }
+            SortOrder::General => (blanks, blanks + float_prefix(key).len()),
+            SortOrder::Month if month(key) > 0 => (blanks, blanks + 3),
+            SortOrder::Month => (blanks, blanks),
+            _ => (0, key.len()),
+        }
     }
+}
 
-    pub fn peek(&self) -> Option<&Line> {
-        self.head.as_ref()
-    }
+/// Sort key `POS1[,POS2]`, positions are zero based
+#[derive(Debug, Clone, PartialEq)]
+struct KeySpec {
+    /// field and character offset of key start
+    start: (usize, usize),
+    /// field and character offset of key end (inclusive), zero offset means end of field,
+    /// `None` means end of line
+    end: Option<(usize, usize)>,
+    /// ordering options
+    options: KeyOptions,
+}
 
-    pub fn drain<W: Write>(&mut self, write: &mut W) {
-        self.for_each(|line| {
-            write
-                .write_all(&line)
-                .and_then(|_| write.write_all(&[b'\n']))
-                .unwrap();
-        });
+impl KeySpec {
+    /// Key spanning the entire line
+    fn whole_line(options: KeyOptions) -> Self {
+        Self {
+            start: (0, 0),
+            end: None,
+            options,
+        }
     }
 
-    pub fn drain_until<W: Write>(&mut self, write: &mut W, limit: &Line) -> bool {
-        while let Some(head) = self.next() {
-            write
-                .write_all(&head)
-                .and_then(|_| write.write_all(&[b'\n']))
-                .unwrap();
+    /// Parse GNU style `F[.C][OPTS][,F[.C][OPTS]]`, keys without options inherit `global`
+    fn parse(spec: &str, global: KeyOptions) -> Result<Self, String> {
+        let mut options = KeyOptions::default();
 
-            if self.peek().is_some() && self.peek().unwrap() > limit {
-                return false;
+        let mut positions = spec.splitn(2, ',');
+        let (field, offset, opts) = parse_position(positions.next().unwrap_or(""))?;
+        if field == 0 {
+            return Err(format!("invalid number at field start: '{}'", spec));
+        }
+        let offset = match offset {
+            None => 0,
+            Some(0) => return Err(format!("character offset is zero: '{}'", spec)),
+            Some(offset) => offset - 1,
+        };
+        options.parse(opts, true)?;
+        let start = (field - 1, offset);
+
+        let end = match positions.next() {
+            None => None,
+            Some(pos) => {
+                let (field, offset, opts) = parse_position(pos)?;
+                if field == 0 {
+                    return Err(format!("invalid number after ',': '{}'", spec));
+                }
+                options.parse(opts, false)?;
+                Some((field - 1, offset.unwrap_or(0)))
             }
+        };
+
+        if options == KeyOptions::default() {
+            options = global;
         }
 
-        true
+        Ok(Self {
+            start,
+            end,
+            options,
+        })
     }
-}
 
-impl PartialEq for SortedChunk {
-    fn eq(&self, other: &Self) -> bool {
-        self.peek() == other.peek()
+    /// Find the key in `line`
+    fn extract<'a>(&self, line: &'a [u8], separator: Option<u8>) -> &'a [u8] {
+        let (start, end) = self.range(line, separator);
+        &line[start..end]
     }
-}
-impl Eq for SortedChunk {}
 
-use std::cmp::Ordering;
+    /// Find the byte range of this key in `line`
+    fn range(&self, line: &[u8], separator: Option<u8>) -> (usize, usize) {
+        let start = self.find_start(line, separator);
+        let end = match self.end {
+            None => line.len(),
+            Some(end) => self.find_end(line, separator, end).max(start),
+        };
 
-impl Ord for SortedChunk {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match (self.peek(), other.peek()) {
-            (Some(l1), Some(l2)) => l2.cmp(&l1),
-            _ => Ordering::Equal, // these cases will not occur
-        }
+        (start, end)
     }
-}
-impl PartialOrd for SortedChunk {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+    fn find_start(&self, line: &[u8], separator: Option<u8>) -> usize {
+        let (field, offset) = self.start;
+        let mut pos = skip_fields(line, separator, field, true);
+
+        if self.options.blanks_start {
+            pos += line[pos..].iter().take_while(|c| is_blank(**c)).count();
+        }
+
+        (pos + offset).min(line.len())
     }
-}
 
-enum SortJob {
-    Sort(Vec<Line>, Arc<Mutex<Vec<SortedChunk>>>, Instant),
-    Merge(Vec<SortedChunk>, Arc<Mutex<Vec<SortedChunk>>>, Instant),
-    MergeFlush(Vec<SortedChunk>, Arc<Mutex<Vec<SortedChunk>>>, Instant),
-}
+    fn find_end(&self, line: &[u8], separator: Option<u8>, end: (usize, usize)) -> usize {
+        let (field, offset) = end;
 
-impl Job for SortJob {
-    fn run(self) {
-        match self {
-            SortJob::Sort(lines, chunks, now) => run_sort(lines, chunks, now),
-            SortJob::Merge(merge, chunks, now) => run_merge(merge, chunks, now),
-            SortJob::MergeFlush(merge, chunks, now) => run_merge_flush(merge, chunks, now),
+        // an offset of zero means the end of the field, i.e. the start of the next one
+        if offset == 0 {
+            return skip_fields(line, separator, field + 1, false);
+        }
+
+        let mut pos = skip_fields(line, separator, field, true);
+        if self.options.blanks_end {
+            pos += line[pos..].iter().take_while(|c| is_blank(**c)).count();
         }
+
+        (pos + offset).min(line.len())
     }
 }
 
-fn run_sort(mut lines: Vec<Line>, chunks: Arc<Mutex<Vec<SortedChunk>>>, now: Instant) {
-    lines.sort_unstable();
+/// Parse `F[.C][OPTS]` into field, optional character offset and options
+fn parse_position(pos: &str) -> Result<(usize, Option<usize>, &str), String> {
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let invalid = || format!("invalid field specification '{}'", pos);
 
-    let chunk = SortedChunk::new(lines);
-    chunks.lock().unwrap().push(chunk);
+    let n = digits(pos);
+    let field = pos[..n].parse().map_err(|_| invalid())?;
+    let rest = &pos[n..];
 
-    eprintln!(
-        "{:>5} threadpool - sorted",
-        Instant::now().duration_since(now).as_millis()
-    );
+    if let Some(rest) = rest.strip_prefix('.') {
+        let n = digits(rest);
+        let offset = rest[..n].parse().map_err(|_| invalid())?;
+        Ok((field, Some(offset), &rest[n..]))
+    } else {
+        Ok((field, None, rest))
+    }
 }
 
-fn run_merge(merge: Vec<SortedChunk>, chunks: Arc<Mutex<Vec<SortedChunk>>>, now: Instant) {
-    let mut merge: BinaryHeap<_> = merge.into_iter().collect();
-    let mut merged = vec![];
+/// Is this byte a field separating blank
+fn is_blank(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
 
-    while let Some(mut chunk) = merge.pop() {
-        match merge.peek().and_then(|c| c.peek()) {
-            Some(limit) => {
-                while let Some(head) = chunk.next() {
-                    merged.push(head);
-                    if chunk.peek().is_some() && chunk.peek().unwrap() > limit {
-                        break;
-                    }
-                }
+/// Advance past `n` fields of `line`, returning the byte position
+///
+/// Without separator, a field consists of leading blanks followed by non-blanks. With a
+/// separator, the separator following the last skipped field is consumed only if `consume_last`.
+fn skip_fields(line: &[u8], separator: Option<u8>, n: usize, consume_last: bool) -> usize {
+    let mut pos = 0;
+
+    for i in 0..n {
+        if pos >= line.len() {
+            break;
+        }
 
-                if chunk.peek().is_some() {
-                    merge.push(chunk);
+        match separator {
+            Some(sep) => {
+                pos += line[pos..].iter().take_while(|c| **c != sep).count();
+                if pos < line.len() && (consume_last || i + 1 < n) {
+                    pos += 1;
                 }
             }
-            None => chunk.for_each(|l| merged.push(l)),
+            None => {
+                pos += line[pos..].iter().take_while(|c| is_blank(**c)).count();
+                pos += line[pos..].iter().take_while(|c| !is_blank(**c)).count();
+            }
         }
     }
 
-    let mut chunk = SortedChunk::new(merged);
-    chunk.merged = true;
-
-    chunks.lock().unwrap().push(chunk);
-
-    eprintln!(
-        "{:>5} threadpool - merged",
-        Instant::now().duration_since(now).as_millis()
-    );
+    pos
 }
 
-fn run_merge_flush(merge: Vec<SortedChunk>, chunks: Arc<Mutex<Vec<SortedChunk>>>, now: Instant) {
-    let mut merge: BinaryHeap<_> = merge.into_iter().collect();
-    let mut tempfile = tempfile().unwrap();
+/// Parse the number at the start of `text` (after blanks) into sign, integer and fraction digits
+///
+/// Leading zeros of the integer part and trailing zeros of the fraction are stripped, so zero
+/// has empty digits. Returns the remaining text after the number.
+fn parse_number(text: &[u8]) -> (bool, &[u8], &[u8], &[u8]) {
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+
+    let mut text = &text[text.iter().take_while(|c| is_blank(**c)).count()..];
+    let negative = text.first() == Some(&b'-');
+    if negative {
+        text = &text[1..];
+    }
 
-    {
-        let mut write = BufWriter::new(&mut tempfile);
+    let n = digits(text);
+    let (mut int, mut rest) = text.split_at(n);
+    while int.first() == Some(&b'0') {
+        int = &int[1..];
+    }
 
-        while let Some(mut chunk) = merge.pop() {
-            let exhausted = match merge.peek().and_then(|c| c.peek()) {
-                Some(limit) => chunk.drain_until(&mut write, limit),
-                None => {
-                    chunk.drain(&mut write);
-                    true
-                }
-            };
-            if !exhausted {
-                merge.push(chunk);
-            }
+    let mut frac: &[u8] = &[];
+    if rest.first() == Some(&b'.') {
+        let n = digits(&rest[1..]);
+        frac = &rest[1..=n];
+        rest = &rest[n + 1..];
+        while frac.last() == Some(&b'0') {
+            frac = &frac[..frac.len() - 1];
         }
     }
 
-    tempfile.seek(SeekFrom::Start(0)).unwrap();
+    (negative, int, frac, rest)
+}
 
-    let iter = BufReader::new(tempfile).split(b'\n');
-    let mut chunk = SortedChunk {
-        merged: true,
-        source: LineIterator::File(iter),
-        head: None,
+/// Compare by string numerical value, `-n`
+///
+/// Numbers are compared digit by digit, so they are not limited in size or precision. Text that
+/// does not start with a number compares equal to zero.
+fn compare_numeric(a: &[u8], b: &[u8]) -> Ordering {
+    let (a_neg, a_int, a_frac, _) = parse_number(a);
+    let (b_neg, b_int, b_frac, _) = parse_number(b);
+
+    let sign = |neg: bool, int: &[u8], frac: &[u8]| match (neg, int.is_empty() && frac.is_empty()) {
+        (_, true) => 0,
+        (true, false) => -1,
+        (false, false) => 1,
     };
-    chunk.next();
+    let a_sign = sign(a_neg, a_int, a_frac);
+    let b_sign = sign(b_neg, b_int, b_frac);
 
-    chunks.lock().unwrap().push(chunk);
+    if a_sign != b_sign || a_sign == 0 {
+        return a_sign.cmp(&b_sign);
+    }
+
+    let magnitude = a_int
+        .len()
+        .cmp(&b_int.len())
+        .then_with(|| a_int.cmp(b_int))
+        .then_with(|| a_frac.cmp(b_frac));
 
-    eprintln!(
-        "{:>5} threadpool - merge_flushed",
-        Instant::now().duration_since(now).as_millis()
-    );
+    if a_sign < 0 {
+        magnitude.reverse()
+    } else {
+        magnitude
+    }
 }
 
-/// Parse arguments, run job, pass return code
-fn main() -> ! {
-    let matches = App::new("sort")
-        .about(USAGE)
-        .arg(
-            Arg::with_name("numeric-sort")
-                .long("numeric-sort")
-                .short("n")
-                .help("compare according to string numerical value"),
-        )
-        .arg(
-            Arg::with_name("batch-size")
-                .long("batch-size")
-                .value_name("NMERGE")
-                .help("merge at most NMERGE inputs at once; for more use temp files")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("buffer-size")
-                .long("buffer-size")
-                .value_name("SIZE")
-                .short("S")
-                .help("use SIZE for main memory buffer")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("parallel")
-                .long("parallel")
-                .value_name("N")
-                .help("change the number of sorts run concurrently to N")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("FILE")
-                .help("sort FILE(s), use - for stdin")
-                .multiple(true)
-                .index(1),
-        )
-        .get_matches();
+/// Find the floating point number at the start of `text` (after blanks), like `strtod`
+///
+/// Besides decimal numbers this accepts infinity, NaN and hexadecimal numbers like `0x1.8p3`.
+fn float_prefix(text: &[u8]) -> &[u8] {
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
 
-    let mut inputs = matches
-        .values_of("FILE")
-        .map(|values| {
-            values
-                .map(|v| {
-                    if v == "-" {
-                        InputArg::Stdin
-                    } else {
-                        InputArg::File(v)
-                    }
-                })
-                .collect()
-        })
-        .unwrap_or_else(Vec::new);
-    if inputs.is_empty() {
-        inputs.push(InputArg::Stdin);
+    let text = &text[text.iter().take_while(|c| is_blank(**c)).count()..];
+    let sign = match text.first() {
+        Some(b'-') | Some(b'+') => 1,
+        _ => 0,
+    };
+    if let Some(len) = hex_float_len(&text[sign..]) {
+        return &text[..sign + len];
     }
 
-    let sort_order = if matches.is_present("numerical-sort") {
-        SortOrder::Numerical
-    } else {
-        SortOrder::Bytes
-    };
+    let mut end = sign;
+    let int = digits(&text[end..]);
+    end += int;
+    let mut frac = 0;
+    if text.get(end) == Some(&b'.') {
+        frac = digits(&text[end + 1..]);
+        end += 1 + frac;
+    }
 
-    let batch_size = matches
-        .value_of("batch-size")
-        .and_then(|n| n.parse::<usize>().ok())
-        .and_then(NonZeroUsize::new)
-        .unwrap_or_else(|| NonZeroUsize::new(100_000).unwrap());
+    if int + frac == 0 {
+        // no digits, could still be infinity or nan
+        let word = &text[sign..];
+        let len = [&b"infinity"[..], b"inf", b"nan"]
+            .iter()
+            .find(|w| word.len() >= w.len() && word[..w.len()].eq_ignore_ascii_case(w))
+            .map(|w| w.len());
+        end = match len {
+            Some(len) => sign + len,
+            None => 0,
+        };
+    } else {
+        end += exponent_len(&text[end..], b'e');
+    }
 
-    let buffer_size = matches
-        .value_of("buffer-size")
-        .and_then(|n| n.parse::<usize>().ok())
-        .and_then(NonZeroUsize::new);
+    &text[..end]
+}
 
-    let sort_threads = matches
-        .value_of("parallel")
-        .and_then(|n| n.parse::<u32>().ok())
-        .and_then(NonZeroU32::new)
-        .unwrap_or_else(|| NonZeroU32::new(num_cpus::get() as u32).unwrap());
+/// Length of the exponent at the start of `text`, e.g. `e-5` for `marker` e, 0 if there is none
+fn exponent_len(text: &[u8], marker: u8) -> usize {
+    match text.first() {
+        Some(c) if c.eq_ignore_ascii_case(&marker) => (),
+        _ => return 0,
+    }
 
-    let payload = Payload {
-        inputs,
-        sort_order,
-        batch_size,
-        buffer_size,
-        sort_threads,
+    let sign = match text.get(1) {
+        Some(b'-') | Some(b'+') => 1,
+        _ => 0,
     };
-
-    sort(payload);
-    exit(0)
-}
-
-/// `sort` handler
-fn sort(payload: Payload) {
-    let mut line_iter = payload
-        .inputs
+    let digits = text[(1 + sign).min(text.len())..]
         .iter()
-        .flat_map(|input_arg| {
-            Input::try_from(input_arg)
-                .map_err(|_| {
-                    eprintln!("...");
-                })
-                .ok()
-        })
-        .flat_map(|input| input.into_bufread().split(b'\n'))
-        .flat_map(|line| line.ok())
-        .map(|line| line.into_boxed_slice()); // save bytes by dropping cap field
-
-    let batch_size = payload.batch_size.get();
-    let buffer_size = payload
-        .buffer_size
-        .map(|b| b.get())
-        .unwrap_or(usize::max_value());
-    eprintln!(
-        "batch_size {}k, buffer_size {}M",
-        batch_size >> 10,
-        buffer_size >> 20
-    );
+        .take_while(|c| c.is_ascii_digit())
+        .count();
 
-    let mut executor = ThreadPool::new(payload.sort_threads);
+    if digits > 0 {
+        1 + sign + digits
+    } else {
+        0
+    }
+}
 
-    let mut exhausted = false;
-    let mut bytes = 0;
-    let mut batches = 0;
-    let chunks = Arc::new(Mutex::new(vec![]));
-    let now = Instant::now();
+/// Length of the hexadecimal floating point number at the start of `text`, e.g. `0x1.8p3`
+fn hex_float_len(text: &[u8]) -> Option<usize> {
+    if text.len() < 2 || !text[..2].eq_ignore_ascii_case(b"0x") {
+        return None;
+    }
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_hexdigit()).count();
+
+    let mut end = 2;
+    let int = digits(&text[end..]);
+    end += int;
+    let mut frac = 0;
+    if text.get(end) == Some(&b'.') {
+        frac = digits(&text[end + 1..]);
+        end += 1 + frac;
+    }
+    if int + frac == 0 {
+        return None; // only the 0 is a number
+    }
 
-    while !exhausted {
-        let mut lines = vec![];
-        let mut line_count = 0;
+    Some(end + exponent_len(&text[end..], b'p'))
+}
 
-        while line_count < batch_size && bytes < buffer_size {
-            if let Some(line) = line_iter.next() {
-                bytes += line.len();
-                line_count += 1;
-                lines.push(line);
-            } else {
-                exhausted = true;
-                break;
+/// Value of a hexadecimal floating point number without sign, as found by `hex_float_len`
+fn hex_float_value(text: &[u8]) -> f64 {
+    let mut mantissa = 0.;
+    let mut exponent: i64 = 0;
+    let mut frac = false;
+
+    let mut rest = &text[2..];
+    while let Some((&c, tail)) = rest.split_first() {
+        match c {
+            b'.' => frac = true,
+            c if c.is_ascii_hexdigit() => {
+                mantissa = mantissa * 16. + (c as char).to_digit(16).unwrap() as f64;
+                if frac {
+                    exponent -= 4;
+                }
             }
+            _ => break,
         }
+        rest = tail;
+    }
 
-        eprintln!(
-            "{:>5} main - collected {} lines",
-            Instant::now().duration_since(now).as_millis(),
-            line_count
-        );
+    if mantissa == 0. {
+        return 0.;
+    }
+    if !rest.is_empty() {
+        // binary exponent, saturating like the value itself
+        let exp = std::str::from_utf8(&rest[1..]).unwrap();
+        exponent += exp.parse::<i64>().unwrap_or(if exp.starts_with('-') {
+            i64::MIN / 2
+        } else {
+            i64::MAX / 2
+        });
+    }
 
-        executor.submit(SortJob::Sort(lines, chunks.clone(), now));
-        batches += 1;
+    let exponent = exponent.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+    mantissa * 2f64.powi(exponent)
+}
+
+/// Parse the floating point number at the start of `text`
+fn parse_float(text: &[u8]) -> Option<f64> {
+    let prefix = float_prefix(text);
+    let (negative, unsigned) = match prefix.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, prefix),
+    };
+
+    if hex_float_len(unsigned).is_some() {
+        let value = hex_float_value(unsigned);
+        return Some(if negative { -value } else { value });
+    }
+
+    std::str::from_utf8(prefix).ok()?.parse().ok()
+}
+
+/// Compare by general numerical value, `-g`
+///
+/// Text that is not a number sorts first, followed by NaN, then all numbers by value.
+fn compare_general(a: &[u8], b: &[u8]) -> Ordering {
+    match (parse_float(a), parse_float(b)) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(a), Some(b)) => match a.partial_cmp(&b) {
+            Some(ordering) => ordering,
+            None => a.is_nan().cmp(&b.is_nan()).reverse(),
+        },
+    }
+}
+
+/// Order of the SI suffix of human readable numbers, 0 when absent
+fn unit_order(suffix: Option<&u8>) -> i32 {
+    match suffix {
+        Some(b'k') | Some(b'K') => 1,
+        Some(b'M') => 2,
+        Some(b'G') => 3,
+        Some(b'T') => 4,
+        Some(b'P') => 5,
+        Some(b'E') => 6,
+        Some(b'Z') => 7,
+        Some(b'Y') => 8,
+        Some(b'R') => 9,
+        Some(b'Q') => 10,
+        _ => 0,
+    }
+}
+
+/// Compare human readable numbers (e.g. 2K 1G), `-h`
+///
+/// Numbers are ordered by sign first, then by SI suffix, then by numerical value.
+fn compare_human(a: &[u8], b: &[u8]) -> Ordering {
+    fn magnitude(text: &[u8]) -> i32 {
+        let (negative, int, frac, rest) = parse_number(text);
+        if int.is_empty() && frac.is_empty() {
+            return 0;
+        }
+
+        // order zero would make negative suffixed numbers larger than unsuffixed ones
+        match (negative, unit_order(rest.first())) {
+            (true, order) => -1 - order,
+            (false, order) => 1 + order,
+        }
+    }
+
+    magnitude(a)
+        .cmp(&magnitude(b))
+        .then_with(|| compare_numeric(a, b))
+}
+
+/// Month number of the month name abbreviation at the start of `text`, 0 for unknown
+fn month(text: &[u8]) -> usize {
+    const MONTHS: [&[u8]; 12] = [
+        b"JAN", b"FEB", b"MAR", b"APR", b"MAY", b"JUN", b"JUL", b"AUG", b"SEP", b"OCT", b"NOV",
+        b"DEC",
+    ];
+
+    let text = &text[text.iter().take_while(|c| is_blank(**c)).count()..];
+    if text.len() < 3 {
+        return 0;
+    }
+
+    MONTHS
+        .iter()
+        .position(|m| text[..3].eq_ignore_ascii_case(m))
+        .map(|pos| pos + 1)
+        .unwrap_or(0)
+}
+
+/// Compare version numbers within text, `-V`
+///
+/// Follows the `filevercmp` rules: hidden files first, file suffixes like `.tar.gz` are only
+/// considered when the rest is equal, digit sequences compare by numerical value and `~` sorts
+/// before anything, even the end of text.
+fn compare_version(a: &[u8], b: &[u8]) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    // special handling for "", "." and ".."
+    for special in &[&b""[..], b".", b".."] {
+        if a == *special {
+            return Ordering::Less;
+        }
+        if b == *special {
+            return Ordering::Greater;
+        }
+    }
+
+    // hidden files go first
+    let (a, b) = match (a[0] == b'.', b[0] == b'.') {
+        (true, false) => return Ordering::Less,
+        (false, true) => return Ordering::Greater,
+        (true, true) => (&a[1..], &b[1..]),
+        (false, false) => (a, b),
+    };
+
+    // cut off file suffixes, unless the remainders are equal
+    let (mut a_cut, mut b_cut) = (strip_file_suffix(a), strip_file_suffix(b));
+    if a_cut == b_cut {
+        a_cut = a;
+        b_cut = b;
+    }
+
+    compare_version_parts(a_cut, b_cut).then_with(|| a.cmp(b))
+}
+
+/// Strip the file suffix, matching `(\.[A-Za-z~][A-Za-z0-9~]*)*$`
+fn strip_file_suffix(text: &[u8]) -> &[u8] {
+    let mut suffix = None;
+    let mut read_alpha = false;
+
+    for (i, c) in text.iter().enumerate() {
+        if read_alpha {
+            read_alpha = false;
+            if !c.is_ascii_alphabetic() && *c != b'~' {
+                suffix = None;
+            }
+        } else if *c == b'.' {
+            read_alpha = true;
+            if suffix.is_none() {
+                suffix = Some(i);
+            }
+        } else if !c.is_ascii_alphanumeric() && *c != b'~' {
+            suffix = None;
+        }
+    }
+
+    &text[..suffix.unwrap_or(text.len())]
+}
+
+/// Compare alternating non-digit and digit parts, like Debian version numbers
+fn compare_version_parts(a: &[u8], b: &[u8]) -> Ordering {
+    // weight of non-digit characters: `~` first, then end of text, letters, other characters
+    fn weight(c: Option<&u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => *c as i32,
+            Some(b'~') => -1,
+            Some(c) => *c as i32 + 256,
+        }
+    }
+    let is_digit = |c: Option<&u8>| c.map(u8::is_ascii_digit).unwrap_or(false);
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let ordering = weight(a.get(i)).cmp(&weight(b.get(j)));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a.get(i)) && is_digit(b.get(j)) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        if is_digit(a.get(i)) {
+            return Ordering::Greater;
+        }
+        if is_digit(b.get(j)) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Seeded hash of a key, for `-R`
+fn random_hash<I: Iterator<Item = u8>>(seed: u64, key: I) -> u64 {
+    // FNV-1a
+    let mut hash = seed ^ 0xcbf2_9ce4_8422_2325;
+    for c in key {
+        hash = (hash ^ c as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+
+    // splitmix64 finalizer, so keys differing in the last byte end up far apart
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Seed for `-R`, read from `--random-source` or random
+fn random_seed(source: Option<&str>) -> io::Result<u64> {
+    match source {
+        Some(filename) => {
+            let mut buf = [0; 8];
+            File::open(filename)?.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        None => Ok(RandomState::new().build_hasher().finish()),
+    }
+}
+
+/// Compare text according to an approximation of locale collation
+///
+/// Alphanumeric characters are compared case-insensitively first, ignoring punctuation and
+/// whitespace. Ties are broken by case (lower case first) and finally by byte value.
+fn compare_locale(a: &[u8], b: &[u8]) -> Ordering {
+    let (a_str, b_str) = match (std::str::from_utf8(a), std::str::from_utf8(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return a.cmp(b),
+    };
+
+    fn primary(s: &str) -> impl Iterator<Item = char> + '_ {
+        s.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+    }
+    fn tertiary(s: &str) -> impl Iterator<Item = bool> + '_ {
+        s.chars()
+            .filter(|c| c.is_alphanumeric())
+            .map(char::is_uppercase)
+    }
+
+    primary(a_str)
+        .cmp(primary(b_str))
+        .then_with(|| tertiary(a_str).cmp(tertiary(b_str)))
+        .then_with(|| a.cmp(b))
+}
+
+/// Does the environment select locale collation, instead of plain byte order
+fn locale_collation() -> bool {
+    let locale = ["LC_ALL", "LC_COLLATE", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty());
+
+    match locale {
+        None => false,
+        Some(locale) => !(locale == "C" || locale == "POSIX" || locale.starts_with("C.")),
+    }
+}
+
+/// Line ordering, shared by all sort and merge stages
+#[derive(Debug, Default)]
+struct Comparator {
+    /// sort keys, in order of precedence
+    keys: Vec<KeySpec>,
+    /// field separator, when absent fields are separated by blanks
+    separator: Option<u8>,
+    /// compare entire lines when all keys are equal, disabled for stable and unique sorts
+    last_resort: Option<KeyOptions>,
+    /// output only the first of lines that compare equal
+    unique: bool,
+}
+
+impl Comparator {
+    /// Compare lines by their keys, falling back to comparing entire lines
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        for key in &self.keys {
+            let ordering = key.options.compare(
+                key.extract(a, self.separator),
+                key.extract(b, self.separator),
+            );
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        match &self.last_resort {
+            Some(options) => options.compare(a, b),
+            None => Ordering::Equal,
+        }
+    }
+
+    /// Underline the parts of `line` that were compared, for `--debug`
+    fn annotate<W: Write>(&self, line: &[u8], write: &mut W) -> io::Result<()> {
+        let mut spans: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let (start, end) = key.range(line, self.separator);
+                let (from, to) = key.options.span(&line[start..end]);
+                (start + from, start + to)
+            })
+            .collect();
+
+        // the last resort comparison is only shown when it differs from the keys
+        if let Some(options) = &self.last_resort {
+            if self.keys != [KeySpec::whole_line(*options)] {
+                spans.push((0, line.len()));
+            }
+        }
+
+        for (start, end) in spans {
+            // keep tabs, so the underline stays aligned
+            let indent: Vec<u8> = line[..start]
+                .iter()
+                .map(|c| if *c == b'\t' { b'\t' } else { b' ' })
+                .collect();
+            write.write_all(&indent)?;
+
+            if start == end {
+                write.write_all(b"^ no match for key\n")?;
+            } else {
+                write.write_all(&vec![b'_'; end - start])?;
+                write.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sort lines in place, keeping only the first of equal lines for unique sorts
+    fn sort<L: AsRef<[u8]>>(&self, lines: &mut Vec<L>) {
+        self.sort_slice(lines);
+
+        if self.unique {
+            lines.dedup_by(|a, b| self.compare(a.as_ref(), b.as_ref()) == Ordering::Equal);
+        }
+    }
+
+    /// Sort lines in place, keeping all lines
+    fn sort_slice<L: AsRef<[u8]>>(&self, lines: &mut [L]) {
+        if self.last_resort.is_some() {
+            // lines that compare equal are identical, so stability is irrelevant
+            lines.sort_unstable_by(|a, b| self.compare(a.as_ref(), b.as_ref()));
+        } else {
+            lines.sort_by(|a, b| self.compare(a.as_ref(), b.as_ref()));
+        }
+    }
+}
+
+enum LineIterator {
+    /// lines read from a (temp) file or stream, and its name for messages
+    File(Split<BufReader<Box<dyn Read + Send>>>, String),
+    /// lines in memory
+    Vec(IntoIter<Line>),
+}
+impl Iterator for LineIterator {
+    type Item = Result<Line, SortError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            LineIterator::File(iter, name) => iter.next().map(|line| {
+                line.map(Vec::into_boxed_slice)
+                    .map_err(|e| SortError::Read(name.clone(), e))
+            }),
+            LineIterator::Vec(iter) => iter.next().map(Ok),
+        }
+    }
+}
+
+struct SortedChunk {
+    compare: Arc<Comparator>,
+    /// index of the first input batch in this chunk, equal lines are kept in this order
+    seq: usize,
+    merged: bool,
+    source: LineIterator,
+    head: Option<Line>,
+    /// read failure, which ended this chunk early
+    error: Option<SortError>,
+}
+
+impl Iterator for SortedChunk {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        let next = match self.source.next() {
+            Some(Ok(line)) => Some(line),
+            Some(Err(e)) => {
+                self.error = Some(e);
+                None
+            }
+            None => None,
+        };
+        std::mem::replace(&mut self.head, next)
+    }
+}
+
+impl SortedChunk {
+    pub fn new(lines: Vec<Line>, seq: usize, compare: Arc<Comparator>) -> Self {
+        let iter = lines.into_iter();
+
+        let mut me = Self {
+            compare,
+            seq,
+            merged: false,
+            source: LineIterator::Vec(iter),
+            head: None,
+            error: None,
+        };
+
+        me.next();
+
+        me
+    }
+
+    /// Chunk of sorted lines separated by `delimiter`, read from the file or stream `name`
+    pub fn from_read(
+        read: Box<dyn Read + Send>,
+        name: &str,
+        delimiter: u8,
+        seq: usize,
+        compare: Arc<Comparator>,
+    ) -> Self {
+        let iter = BufReader::new(read).split(delimiter);
+
+        let mut me = Self {
+            compare,
+            seq,
+            merged: true,
+            source: LineIterator::File(iter, name.to_string()),
+            head: None,
+            error: None,
+        };
+
+        me.next();
+
+        me
+    }
+
+    pub fn is_flushed(&self) -> bool {
+        matches!(self.source, LineIterator::File(..))
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    pub fn peek(&self) -> Option<&Line> {
+        self.head.as_ref()
+    }
+
+    /// Report the read failure that ended this chunk, if any
+    pub fn check(&mut self) -> Result<(), SortError> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Remaining lines of a chunk in memory, `None` for chunks read from a file
+    fn into_lines(self) -> Option<Vec<Line>> {
+        match self.source {
+            LineIterator::Vec(iter) => Some(self.head.into_iter().chain(iter).collect()),
+            LineIterator::File(..) => None,
+        }
+    }
+}
+
+impl PartialEq for SortedChunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for SortedChunk {}
+
+/// Max-heap ordering: the chunk with the smallest head is the greatest, ties are broken by input
+/// order
+impl Ord for SortedChunk {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.peek(), other.peek()) {
+            (Some(l1), Some(l2)) => self
+                .compare
+                .compare(l2, l1)
+                .then_with(|| other.seq.cmp(&self.seq)),
+            _ => Ordering::Equal, // these cases will not occur
+        }
+    }
+}
+impl PartialOrd for SortedChunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// k-way merge of sorted chunks, passing all lines in order to `emit`
+///
+/// Equal lines are taken from the chunk with the lowest sequence number first, so merging is
+/// stable. For unique sorts only the first line of equal lines is emitted, also when they
+/// originate from different chunks. Stops at the first read or emit failure.
+fn merge_chunks<F: FnMut(Line) -> Result<(), SortError>>(
+    chunks: Vec<SortedChunk>,
+    compare: &Comparator,
+    mut emit: F,
+) -> Result<(), SortError> {
+    let mut heap = BinaryHeap::new();
+    for mut chunk in chunks {
+        if chunk.peek().is_some() {
+            heap.push(chunk);
+        } else {
+            chunk.check()?;
+        }
+    }
+
+    // for unique sorts, hold back the last line to compare it with its successors
+    let mut pending: Option<Line> = None;
+    let mut output = |line: Line| {
+        if !compare.unique {
+            return emit(line);
+        }
+        match pending.take() {
+            Some(prev) if compare.compare(&prev, &line) == Ordering::Equal => pending = Some(prev),
+            Some(prev) => {
+                pending = Some(line);
+                return emit(prev);
+            }
+            None => pending = Some(line),
+        }
+        Ok(())
+    };
+
+    while let Some(mut chunk) = heap.pop() {
+        // keep draining this chunk as long as it precedes all others
+        while let Some(line) = chunk.next() {
+            output(line)?;
+
+            if chunk.peek().is_none() {
+                chunk.check()?;
+                break;
+            }
+            if heap.peek().map(|top| *top > chunk).unwrap_or(false) {
+                heap.push(chunk);
+                break;
+            }
+        }
+    }
+
+    match pending {
+        Some(line) => emit(line),
+        None => Ok(()),
+    }
+}
+
+/// Telemetry of a run, reported with `--stats`
+#[derive(Default)]
+struct Stats {
+    /// chunks sorted in memory
+    chunks_sorted: AtomicUsize,
+    /// merges of chunks, in memory or into temp files
+    merges: AtomicUsize,
+    /// bytes written to temp files
+    bytes_spilled: AtomicU64,
+    /// wall time per phase
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl Stats {
+    /// Record the wall time of a phase started at `start`, returns the start of the next phase
+    fn phase(&self, name: &'static str, start: Instant) -> Instant {
+        let now = Instant::now();
+        self.phases
+            .lock()
+            .unwrap()
+            .push((name, now.duration_since(start)));
+        now
+    }
+
+    /// Peak resident memory of this process, in bytes
+    fn peak_memory() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb << 10)
+    }
+
+    /// Print the report to stderr
+    fn report(&self) {
+        eprintln!(
+            "sort: chunks sorted {}",
+            self.chunks_sorted.load(AtomicOrdering::Relaxed)
+        );
+        eprintln!("sort: merges {}", self.merges.load(AtomicOrdering::Relaxed));
+        eprintln!(
+            "sort: bytes spilled {}",
+            self.bytes_spilled.load(AtomicOrdering::Relaxed)
+        );
+        match Self::peak_memory() {
+            Some(bytes) => eprintln!("sort: peak memory {}", bytes),
+            None => eprintln!("sort: peak memory unknown"),
+        }
+        for (name, duration) in self.phases.lock().unwrap().iter() {
+            eprintln!("sort: phase {} {}ms", name, duration.as_millis());
+        }
+    }
+}
+
+/// Chunks produced by the jobs, or the first failure of a job
+type Chunks = Arc<Mutex<Result<Vec<SortedChunk>, SortError>>>;
+
+/// Take the chunks produced by the jobs, or the failure
+fn take_chunks(chunks: &Chunks) -> Result<Vec<SortedChunk>, SortError> {
+    std::mem::replace(chunks.lock().unwrap().deref_mut(), Ok(vec![]))
+}
+
+/// How chunks are written to temp files
+struct Spill {
+    /// line delimiter, so lines containing newlines survive the round trip
+    delimiter: u8,
+    /// directories for temp files, used round-robin, the default temp dir when empty
+    temp_dirs: Vec<PathBuf>,
+    /// index of the next directory to use
+    next_dir: AtomicUsize,
+    /// compress temp files with this program, decompress with `PROG -d`
+    compress_program: Option<String>,
+}
+
+impl Spill {
+    fn new(payload: &Payload) -> Self {
+        Self {
+            delimiter: payload.delimiter,
+            temp_dirs: payload.temp_dirs.clone(),
+            next_dir: AtomicUsize::new(0),
+            compress_program: payload.compress_program.clone(),
+        }
+    }
+
+    /// Directory for the next temp file
+    fn next_dir(&self) -> PathBuf {
+        if self.temp_dirs.is_empty() {
+            std::env::temp_dir()
+        } else {
+            let index = self.next_dir.fetch_add(1, AtomicOrdering::Relaxed);
+            self.temp_dirs[index % self.temp_dirs.len()].clone()
+        }
+    }
+
+    /// Create a temp file, and a writer into it that compresses if requested
+    fn create(&self) -> Result<(File, SpillWriter), SortError> {
+        let dir = self.next_dir();
+        let file = tempfile_in(&dir).map_err(|e| SortError::TempFile(dir.clone(), e))?;
+        let clone = file.try_clone().map_err(|e| SortError::TempFile(dir, e))?;
+
+        let writer = match &self.compress_program {
+            None => SpillWriter {
+                write: BufWriter::new(Box::new(clone)),
+                compressor: None,
+            },
+            Some(program) => {
+                let mut child = Command::new(program)
+                    .stdin(Stdio::piped())
+                    .stdout(clone)
+                    .spawn()
+                    .map_err(|e| SortError::Compress(program.clone(), e))?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                SpillWriter {
+                    write: BufWriter::new(Box::new(stdin)),
+                    compressor: Some(child),
+                }
+            }
+        };
+
+        Ok((file, writer))
+    }
+
+    /// Read back a temp file written by a `SpillWriter`
+    fn open(&self, mut file: File) -> Result<Box<dyn Read + Send>, SortError> {
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| SortError::Read(TEMP_FILE.to_string(), e))?;
+
+        match &self.compress_program {
+            None => Ok(Box::new(file)),
+            Some(program) => {
+                let mut child = Command::new(program)
+                    .arg("-d")
+                    .stdin(file)
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| SortError::Compress(program.clone(), e))?;
+                let stdout = child.stdout.take().expect("stdout is piped");
+                Ok(Box::new(Decompressor { child, stdout }))
+            }
+        }
+    }
+}
+
+/// Writer into a temp file, possibly through the stdin of a compressor process
+struct SpillWriter {
+    write: BufWriter<Box<dyn Write + Send>>,
+    compressor: Option<Child>,
+}
+
+impl SpillWriter {
+    /// Flush all data into the temp file, waiting for the compressor to exit
+    fn finish(self) -> io::Result<()> {
+        let SpillWriter { write, compressor } = self;
+        // dropping the writer closes the stdin of the compressor
+        drop(write.into_inner().map_err(|e| e.into_error())?);
+
+        if let Some(mut child) = compressor {
+            let status = child.wait()?;
+            if !status.success() {
+                let msg = format!("compress program failed: {}", status);
+                return Err(io::Error::other(msg));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for SpillWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// Output of a decompressor process, checking its exit status at the end
+struct Decompressor {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Decompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                let msg = format!("decompress program failed: {}", status);
+                return Err(io::Error::other(msg));
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        // do not leave zombies behind when the chunk is not read to the end
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+enum SortJob {
+    Sort(Vec<Line>, usize, Arc<Comparator>, Chunks, Arc<Stats>),
+    Merge(Vec<SortedChunk>, Arc<Comparator>, Chunks, Arc<Stats>),
+    MergeFlush(
+        Vec<SortedChunk>,
+        Arc<Comparator>,
+        Chunks,
+        Arc<Spill>,
+        Arc<Stats>,
+    ),
+    /// merge a group of inputs into a temp file, opening them only when the job runs
+    MergeInputs(
+        Vec<InputArg<String>>,
+        usize,
+        Arc<Comparator>,
+        Chunks,
+        Arc<Spill>,
+        Arc<Stats>,
+    ),
+}
+
+impl Job for SortJob {
+    fn run(self) {
+        let chunks = match &self {
+            SortJob::Sort(.., chunks, _) => chunks.clone(),
+            SortJob::Merge(.., chunks, _) => chunks.clone(),
+            SortJob::MergeFlush(.., chunks, _, _) => chunks.clone(),
+            SortJob::MergeInputs(.., chunks, _, _) => chunks.clone(),
+        };
+
+        // skip the remaining jobs after a failure
+        if chunks.lock().unwrap().is_err() {
+            return;
+        }
+
+        let result = match self {
+            SortJob::Sort(lines, seq, compare, _, stats) => {
+                Ok(run_sort(lines, seq, compare, stats))
+            }
+            SortJob::Merge(merge, compare, _, stats) => run_merge(merge, compare, stats),
+            SortJob::MergeFlush(merge, compare, _, spill, stats) => {
+                run_merge_flush(merge, compare, spill, stats)
+            }
+            SortJob::MergeInputs(inputs, seq, compare, _, spill, stats) => {
+                run_merge_inputs(inputs, seq, compare, spill, stats)
+            }
+        };
+
+        let mut lock = chunks.lock().unwrap();
+        match (lock.deref_mut(), result) {
+            (Ok(done), Ok(chunk)) => done.push(chunk),
+            (Ok(_), Err(e)) => *lock = Err(e),
+            (Err(_), _) => (), // keep the first failure
+        }
+    }
+}
+
+fn run_sort(
+    mut lines: Vec<Line>,
+    seq: usize,
+    compare: Arc<Comparator>,
+    stats: Arc<Stats>,
+) -> SortedChunk {
+    compare.sort(&mut lines);
+
+    stats.chunks_sorted.fetch_add(1, AtomicOrdering::Relaxed);
+
+    SortedChunk::new(lines, seq, compare)
+}
+
+fn run_merge(
+    merge: Vec<SortedChunk>,
+    compare: Arc<Comparator>,
+    stats: Arc<Stats>,
+) -> Result<SortedChunk, SortError> {
+    let seq = merge.iter().map(|chunk| chunk.seq).min().unwrap_or(0);
+    let mut merged = vec![];
+
+    merge_chunks(merge, &compare, |line| {
+        merged.push(line);
+        Ok(())
+    })?;
+
+    let mut chunk = SortedChunk::new(merged, seq, compare);
+    chunk.merged = true;
+
+    stats.merges.fetch_add(1, AtomicOrdering::Relaxed);
+
+    Ok(chunk)
+}
+
+fn run_merge_flush(
+    merge: Vec<SortedChunk>,
+    compare: Arc<Comparator>,
+    spill: Arc<Spill>,
+    stats: Arc<Stats>,
+) -> Result<SortedChunk, SortError> {
+    let seq = merge.iter().map(|chunk| chunk.seq).min().unwrap_or(0);
+    let (tempfile, mut write) = spill.create()?;
+    let write_failed = |e| SortError::Write(TEMP_FILE.to_string(), e);
+
+    merge_chunks(merge, &compare, |line| {
+        write
+            .write_all(&line)
+            .and_then(|_| write.write_all(&[spill.delimiter]))
+            .map_err(write_failed)
+    })?;
+    write.finish().map_err(write_failed)?;
+
+    // compressed size, when compressing
+    let spilled = tempfile.metadata().map_err(write_failed)?.len();
+
+    let read = spill.open(tempfile)?;
+    let chunk = SortedChunk::from_read(read, TEMP_FILE, spill.delimiter, seq, compare);
+
+    stats.merges.fetch_add(1, AtomicOrdering::Relaxed);
+    stats
+        .bytes_spilled
+        .fetch_add(spilled, AtomicOrdering::Relaxed);
+
+    Ok(chunk)
+}
+
+fn run_merge_inputs(
+    inputs: Vec<InputArg<String>>,
+    seq: usize,
+    compare: Arc<Comparator>,
+    spill: Arc<Spill>,
+    stats: Arc<Stats>,
+) -> Result<SortedChunk, SortError> {
+    let group = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input_arg)| {
+            let input_arg = match input_arg {
+                InputArg::Stdin => InputArg::Stdin,
+                InputArg::File(filename) => InputArg::File(filename.as_str()),
+            };
+            open_sorted(&input_arg, spill.delimiter, seq + i, compare.clone())
+        })
+        .collect::<Result<_, _>>()?;
+
+    run_merge_flush(group, compare, spill, stats)
+}
+
+/// Open an already sorted input as a chunk
+fn open_sorted(
+    input_arg: &InputArg<&str>,
+    delimiter: u8,
+    seq: usize,
+    compare: Arc<Comparator>,
+) -> Result<SortedChunk, SortError> {
+    let name = input_name(input_arg);
+    let input = Input::try_from(input_arg).map_err(|e| SortError::Read(name.to_string(), e))?;
+
+    Ok(SortedChunk::from_read(
+        input.into_read(),
+        name,
+        delimiter,
+        seq,
+        compare,
+    ))
+}
+
+/// Whether the output file is also one of the inputs
+///
+/// Like GNU sort, files are compared by device and inode, so hard links and bind mounts of an
+/// input are detected too. Standard input is compared by the file it is redirected from.
+fn output_is_input(output_arg: &OutputArg<&str>, inputs: &[InputArg<&str>]) -> bool {
+    let file_id = |path: &Path| {
+        std::fs::metadata(path)
+            .ok()
+            .map(|metadata| (metadata.dev(), metadata.ino()))
+    };
+
+    let target = match output_arg {
+        OutputArg::File(filename, _) => file_id(Path::new(filename)),
+        OutputArg::Stdout => None,
+    };
+    let target = match target {
+        Some(target) => target,
+        None => return false,
+    };
+
+    inputs.iter().any(|input_arg| {
+        let input = match input_arg {
+            InputArg::File(filename) => file_id(Path::new(filename)),
+            InputArg::Stdin => file_id(Path::new("/dev/stdin")),
+        };
+        input == Some(target)
+    })
+}
+
+/// Sort output, written via a temp file when it is also one of the inputs
+struct Destination {
+    write: BufWriter<Output>,
+    /// temp file in the same directory and the output path it will replace
+    replace: Option<(NamedTempFile, PathBuf)>,
+}
+
+impl Destination {
+    /// Open the output, but do not truncate it yet when it is also an input
+    fn open(output_arg: &OutputArg<&str>, inputs: &[InputArg<&str>]) -> io::Result<Self> {
+        let filename = match output_arg {
+            OutputArg::File(filename, _) => *filename,
+            OutputArg::Stdout => {
+                return Ok(Self {
+                    write: BufWriter::new(Output::try_from(output_arg)?),
+                    replace: None,
+                })
+            }
+        };
+
+        if !output_is_input(output_arg, inputs) {
+            return Ok(Self {
+                write: BufWriter::new(Output::try_from(output_arg)?),
+                replace: None,
+            });
+        }
+
+        // replace the file a symlink points to, not the link itself
+        let filename = std::fs::canonicalize(filename)?;
+        let dir = match filename.parent() {
+            Some(dir) => dir,
+            None => Path::new("/"),
+        };
+        let tempfile = NamedTempFile::new_in(dir)?;
+        let output = Output::File(tempfile.as_file().try_clone()?);
+
+        Ok(Self {
+            write: BufWriter::new(output),
+            replace: Some((tempfile, filename)),
+        })
+    }
+
+    /// Flush the output, and atomically move the temp file over the output path
+    fn finish(self) -> io::Result<()> {
+        let Destination { write, replace } = self;
+        write.into_inner().map_err(|e| e.into_error())?;
+
+        if let Some((tempfile, filename)) = replace {
+            let permissions = std::fs::metadata(&filename)?.permissions();
+            std::fs::set_permissions(tempfile.path(), permissions)?;
+            tempfile.persist(filename)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes lines to the output, annotating the keys of each line when `debug`
+struct LineWriter<'a> {
+    destination: Destination,
+    /// output name for messages
+    name: &'a str,
+    delimiter: u8,
+    debug: bool,
+}
+
+impl<'a> LineWriter<'a> {
+    fn open(
+        output_arg: &OutputArg<&'a str>,
+        inputs: &[InputArg<&str>],
+        delimiter: u8,
+        debug: bool,
+    ) -> Result<Self, SortError> {
+        let name = match output_arg {
+            OutputArg::File(filename, _) => filename,
+            OutputArg::Stdout => "standard output",
+        };
+        let destination = Destination::open(output_arg, inputs)
+            .map_err(|e| SortError::Open(name.to_string(), e))?;
+
+        Ok(Self {
+            destination,
+            name,
+            delimiter,
+            debug,
+        })
+    }
+
+    fn write(&mut self, line: &[u8], compare: &Comparator) -> Result<(), SortError> {
+        let (delimiter, debug) = (self.delimiter, self.debug);
+        let write = &mut self.destination.write;
+        write
+            .write_all(line)
+            .and_then(|_| write.write_all(&[delimiter]))
+            .and_then(|_| {
+                if debug {
+                    compare.annotate(line, write)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| SortError::Output(self.name.to_string(), e))
+    }
+
+    fn finish(self) -> Result<(), SortError> {
+        let name = self.name;
+        self.destination
+            .finish()
+            .map_err(|e| SortError::Output(name.to_string(), e))
+    }
+}
+
+/// Merge the chunks into the output
+fn write_output(
+    chunks: Vec<SortedChunk>,
+    compare: &Comparator,
+    output_arg: &OutputArg<&str>,
+    inputs: &[InputArg<&str>],
+    delimiter: u8,
+    debug: bool,
+) -> Result<(), SortError> {
+    let mut writer = LineWriter::open(output_arg, inputs, delimiter, debug)?;
+    merge_chunks(chunks, compare, |line| writer.write(&line, compare))?;
+    writer.finish()
+}
+
+/// Merge chunks in passes of at most `batch_size` chunks, until at most `batch_size` are left
+///
+/// Only chunks of consecutive input batches are merged together, so equal lines keep their
+/// order. Groups containing file backed chunks are merged into temp files, to limit the number
+/// of open files.
+fn reduce_chunks(
+    mut chunks: Vec<SortedChunk>,
+    batch_size: usize,
+    compare: &Arc<Comparator>,
+    threads: NonZeroU32,
+    spill: &Arc<Spill>,
+    stats: &Arc<Stats>,
+) -> Result<Vec<SortedChunk>, SortError> {
+    while chunks.len() > batch_size {
+        chunks.sort_by_key(|chunk| chunk.seq);
+
+        let merged = Arc::new(Mutex::new(Ok(vec![])));
+        let mut executor = ThreadPool::new(threads);
+        let mut iter = chunks.into_iter();
+
+        loop {
+            let group: Vec<_> = iter.by_ref().take(batch_size).collect();
+            if group.is_empty() {
+                break;
+            }
+
+            if group.iter().any(SortedChunk::is_flushed) {
+                executor.submit(SortJob::MergeFlush(
+                    group,
+                    compare.clone(),
+                    merged.clone(),
+                    spill.clone(),
+                    stats.clone(),
+                ));
+            } else {
+                executor.submit(SortJob::Merge(
+                    group,
+                    compare.clone(),
+                    merged.clone(),
+                    stats.clone(),
+                ));
+            }
+        }
+
+        executor.finish();
+        chunks = take_chunks(&merged)?;
+    }
+
+    Ok(chunks)
+}
+
+/// Merge chunks in memory in parallel, into a single chunk
+///
+/// Splitter lines, sampled from all chunks, partition every chunk into key ranges. Each range is
+/// merged by its own job and the results are concatenated in order. Lines that compare equal
+/// always end up in the same range, so stable and unique merges are not affected. Chunks read
+/// from files cannot be partitioned, these are returned as is.
+fn merge_parallel(
+    chunks: Vec<SortedChunk>,
+    compare: &Arc<Comparator>,
+    threads: NonZeroU32,
+    stats: &Arc<Stats>,
+) -> Result<Vec<SortedChunk>, SortError> {
+    let partitions = threads.get() as usize;
+    if chunks.len() < 2 || partitions < 2 || chunks.iter().any(SortedChunk::is_flushed) {
+        return Ok(chunks);
+    }
+
+    let runs: Vec<_> = chunks
+        .into_iter()
+        .map(|chunk| (chunk.seq, chunk.into_lines().unwrap_or_default()))
+        .collect();
+
+    // evenly spaced samples of every run
+    let mut samples: Vec<&Line> = runs
+        .iter()
+        .flat_map(|(_, lines)| {
+            let step = (lines.len() / (partitions * SAMPLES_PER_PARTITION)).max(1);
+            lines.iter().step_by(step)
+        })
+        .collect();
+    samples.sort_by(|a, b| compare.compare(a, b));
+    samples.dedup_by(|a, b| compare.compare(a, b) == Ordering::Equal);
+
+    let step = (samples.len() / partitions).max(1);
+    let splitters: Vec<Line> = samples
+        .iter()
+        .skip(step)
+        .step_by(step)
+        .take(partitions - 1)
+        .map(|line| (*line).clone())
+        .collect();
+
+    // cut every run in front of each splitter, equal lines go to the same side everywhere
+    let mut groups: Vec<Vec<SortedChunk>> = (0..=splitters.len()).map(|_| vec![]).collect();
+    for (seq, mut lines) in runs {
+        for (index, splitter) in splitters.iter().enumerate().rev() {
+            let at =
+                lines.partition_point(|line| compare.compare(line, splitter) == Ordering::Less);
+            let part = lines.split_off(at);
+            if !part.is_empty() {
+                groups[index + 1].push(SortedChunk::new(part, seq, compare.clone()));
+            }
+        }
+        if !lines.is_empty() {
+            groups[0].push(SortedChunk::new(lines, seq, compare.clone()));
+        }
+    }
+
+    let merged = Arc::new(Mutex::new(Ok(vec![])));
+    let mut executor = ThreadPool::new(threads);
+    for group in groups.into_iter().filter(|group| !group.is_empty()) {
+        executor.submit(SortJob::Merge(
+            group,
+            compare.clone(),
+            merged.clone(),
+            stats.clone(),
+        ));
+    }
+    executor.finish();
+
+    // the ranges are disjoint, so ordering them by their first line restores the key order
+    let mut ranges = take_chunks(&merged)?;
+    ranges.sort_by(|a, b| match (a.peek(), b.peek()) {
+        (Some(a), Some(b)) => compare.compare(a, b),
+        _ => Ordering::Equal, // merged ranges are never empty
+    });
+
+    let mut lines = vec![];
+    for range in ranges {
+        lines.extend(range.into_lines().unwrap_or_default());
+    }
+
+    let mut chunk = SortedChunk::new(lines, 0, compare.clone());
+    chunk.merged = true;
+
+    Ok(vec![chunk])
+}
+
+/// Parse arguments, run job, pass return code
+fn main() -> ! {
+    let matches = App::new("sort")
+        .about(USAGE)
+        .arg(
+            Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .short("f")
+                .help("fold lower case to upper case characters"),
+        )
+        .arg(
+            Arg::with_name("general-numeric-sort")
+                .long("general-numeric-sort")
+                .short("g")
+                .help("compare according to general numerical value"),
+        )
+        .arg(
+            Arg::with_name("human-numeric-sort")
+                .long("human-numeric-sort")
+                .short("h")
+                .help("compare human readable numbers (e.g., 2K 1G)"),
+        )
+        .arg(
+            Arg::with_name("month-sort")
+                .long("month-sort")
+                .short("M")
+                .help("compare (unknown) < 'JAN' < ... < 'DEC'"),
+        )
+        .arg(
+            Arg::with_name("numeric-sort")
+                .long("numeric-sort")
+                .short("n")
+                .help("compare according to string numerical value"),
+        )
+        .arg(
+            Arg::with_name("merge")
+                .long("merge")
+                .short("m")
+                .help("merge already sorted files; do not sort"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .help("write result to FILE instead of standard output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("random-sort")
+                .long("random-sort")
+                .short("R")
+                .help("shuffle, but group identical keys"),
+        )
+        .arg(
+            Arg::with_name("random-source")
+                .long("random-source")
+                .value_name("FILE")
+                .help("get random bytes from FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .short("r")
+                .help("reverse the result of comparisons"),
+        )
+        .arg(
+            Arg::with_name("stable")
+                .long("stable")
+                .short("s")
+                .help("stabilize sort by disabling last-resort comparison"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("report chunks, merges, spilled bytes, peak memory and phase timings"),
+        )
+        .arg(
+            Arg::with_name("unique")
+                .long("unique")
+                .short("u")
+                .help("output only the first of an equal run"),
+        )
+        .arg(
+            Arg::with_name("version-sort")
+                .long("version-sort")
+                .short("V")
+                .help("natural sort of (version) numbers within text"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .short("c")
+                .help("check for sorted input; do not sort"),
+        )
+        .arg(
+            Arg::with_name("check-when")
+                .long("check")
+                .value_name("WHEN")
+                .help("like -c, with WHEN diagnose-first (default), quiet or silent")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["diagnose-first", "quiet", "silent"]),
+        )
+        .arg(
+            Arg::with_name("check-quiet")
+                .short("C")
+                .help("like -c, but do not report first bad line"),
+        )
+        .arg(
+            Arg::with_name("ignore-leading-blanks")
+                .long("ignore-leading-blanks")
+                .short("b")
+                .help("ignore leading blanks"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("annotate the part of the line used to sort"),
+        )
+        .arg(
+            Arg::with_name("dictionary-order")
+                .long("dictionary-order")
+                .short("d")
+                .help("consider only blanks and alphanumeric characters"),
+        )
+        .arg(
+            Arg::with_name("ignore-nonprinting")
+                .long("ignore-nonprinting")
+                .short("i")
+                .help("consider only printable characters"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEYDEF")
+                .help("sort via a key; KEYDEF gives location and type")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(false),
+        )
+        .arg(
+            Arg::with_name("field-separator")
+                .long("field-separator")
+                .short("t")
+                .value_name("SEP")
+                .help("use SEP instead of non-blank to blank transition")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("batch-size")
+                .long("batch-size")
+                .value_name("NMERGE")
+                .help("merge at most NMERGE inputs at once; for more use temp files")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("buffer-size")
+                .long("buffer-size")
+                .value_name("SIZE")
+                .short("S")
+                .help("use SIZE for main memory buffer, e.g. 512M, 2G or 50%; KiB by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("parallel")
+                .long("parallel")
+                .value_name("N")
+                .help("change the number of sorts run concurrently to N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("temporary-directory")
+                .long("temporary-directory")
+                .short("T")
+                .value_name("DIR")
+                .help("use DIR for temporaries, not $TMPDIR or /tmp; multiple options use the directories in turn")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("compress-program")
+                .long("compress-program")
+                .value_name("PROG")
+                .help("compress temporaries with PROG; decompress them with PROG -d")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("zero-terminated")
+                .long("zero-terminated")
+                .short("z")
+                .help("line delimiter is NUL, not newline"),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .help("sort FILE(s), use - for stdin")
+                .multiple(true)
+                .index(1),
+        )
+        .get_matches();
+
+    let mut inputs = matches
+        .values_of("FILE")
+        .map(|values| {
+            values
+                .map(|v| {
+                    if v == "-" {
+                        InputArg::Stdin
+                    } else {
+                        InputArg::File(v)
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+    if inputs.is_empty() {
+        inputs.push(InputArg::Stdin);
+    }
+
+    // collect the global ordering options, so they are validated like key options
+    let global_opts: String = [
+        ("ignore-leading-blanks", 'b'),
+        ("dictionary-order", 'd'),
+        ("ignore-case", 'f'),
+        ("general-numeric-sort", 'g'),
+        ("human-numeric-sort", 'h'),
+        ("ignore-nonprinting", 'i'),
+        ("month-sort", 'M'),
+        ("numeric-sort", 'n'),
+        ("random-sort", 'R'),
+        ("reverse", 'r'),
+        ("version-sort", 'V'),
+    ]
+    .iter()
+    .filter(|(name, _)| matches.is_present(name))
+    .map(|(_, letter)| letter)
+    .collect();
+
+    let mut global = KeyOptions::default();
+    let keys = global
+        .parse(&global_opts, true)
+        .and_then(|_| global.parse(&global_opts, false))
+        .and_then(|_| {
+            matches
+                .values_of("key")
+                .map(|values| values.map(|v| KeySpec::parse(v, global)).collect())
+                .unwrap_or_else(|| Ok(vec![]))
+        });
+    let mut keys: Vec<_> = match keys {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("sort: {}", e);
+            exit(2);
+        }
+    };
+    if keys.is_empty() {
+        keys.push(KeySpec::whole_line(global));
+    }
+
+    let random_source = matches.value_of("random-source");
+    let seed = match random_seed(random_source) {
+        Ok(seed) => seed,
+        Err(e) => {
+            eprintln!("sort: {}: {}", random_source.unwrap_or("-"), e);
+            exit(2);
+        }
+    };
+    // all keys share the seed, so chunks sorted in parallel and merged later agree on the order
+    for key in &mut keys {
+        if let SortOrder::Random(key_seed) = &mut key.options.order {
+            *key_seed = seed;
+        }
+    }
+
+    let unique = matches.is_present("unique");
+    let stable = matches.is_present("stable");
+
+    let mut last_resort = KeyOptions {
+        reverse: global.reverse,
+        ..KeyOptions::default()
+    };
+    if locale_collation() {
+        last_resort.order = SortOrder::Locale;
+        keys.iter_mut()
+            .filter(|key| key.options.order == SortOrder::Bytes)
+            .for_each(|key| key.options.order = SortOrder::Locale);
+    }
+    let last_resort = if stable || unique {
+        None
+    } else {
+        Some(last_resort)
+    };
+
+    let separator = match matches.value_of("field-separator").map(str::as_bytes) {
+        None => None,
+        Some([sep]) => Some(*sep),
+        Some(b"\\0") => Some(b'\0'),
+        Some(_) => {
+            eprintln!("sort: the field separator must be a single byte");
+            exit(2);
+        }
+    };
+
+    let comparator = Comparator {
+        keys,
+        separator,
+        last_resort,
+        unique,
+    };
+
+    let batch_size = match matches.value_of("batch-size").map(str::parse::<usize>) {
+        None => DEFAULT_BATCH_SIZE,
+        Some(Ok(n)) if n >= 2 => n,
+        Some(_) => {
+            eprintln!("sort: invalid --batch-size argument; minimum is 2");
+            exit(2);
+        }
+    };
+    let batch_size = NonZeroUsize::new(batch_size).unwrap();
+
+    let buffer_size = matches.value_of("buffer-size").map(|arg| {
+        match parse_size(arg).and_then(NonZeroUsize::new) {
+            Some(size) => size,
+            None => {
+                eprintln!("sort: invalid -S argument '{}'", arg);
+                exit(2);
+            }
+        }
+    });
+
+    let sort_threads = matches
+        .value_of("parallel")
+        .and_then(|n| n.parse::<u32>().ok())
+        .and_then(NonZeroU32::new)
+        .unwrap_or_else(|| NonZeroU32::new(num_cpus::get() as u32).unwrap());
+
+    let mode = match matches.value_of("check-when") {
+        _ if matches.is_present("check-quiet") => Mode::Check { quiet: true },
+        Some("quiet") | Some("silent") => Mode::Check { quiet: true },
+        _ if matches.is_present("check") || matches.is_present("check-when") => {
+            Mode::Check { quiet: false }
+        }
+        _ if matches.is_present("merge") => Mode::Merge,
+        _ => Mode::Sort,
+    };
 
-        if !exhausted && (bytes > buffer_size || batches > N_WAY_MERGE) {
+    if let Mode::Check { .. } = mode {
+        if inputs.len() > 1 {
+            eprintln!("sort: extra operand, only one input may be checked");
+            exit(2);
+        }
+        if matches.is_present("output") {
+            eprintln!("sort: options '-co' are incompatible");
+            exit(2);
+        }
+    }
+
+    let output = match matches.value_of("output") {
+        Some(filename) => OutputArg::File(filename, false),
+        None => OutputArg::Stdout,
+    };
+
+    let debug = matches.is_present("debug");
+    if debug {
+        match comparator
+            .last_resort
+            .or(comparator.keys.first().map(|k| k.options))
+        {
+            Some(options) if options.order == SortOrder::Locale => {
+                eprintln!("sort: text ordering performed using locale collation")
+            }
+            _ => eprintln!("sort: text ordering performed using simple byte comparison"),
+        }
+    }
+    let show_stats = matches.is_present("stats");
+
+    let delimiter = if matches.is_present("zero-terminated") {
+        b'\0'
+    } else {
+        b'\n'
+    };
+
+    let temp_dirs = matches
+        .values_of("temporary-directory")
+        .map(|dirs| dirs.map(PathBuf::from).collect())
+        .unwrap_or_default();
+    let compress_program = matches.value_of("compress-program").map(String::from);
+
+    let payload = Payload {
+        inputs,
+        output,
+        mode,
+        debug,
+        comparator,
+        batch_size,
+        buffer_size,
+        sort_threads,
+        delimiter,
+        temp_dirs,
+        compress_program,
+    };
+
+    let stats = Arc::new(Stats::default());
+
+    let code = match run(payload, &stats) {
+        Ok(code) => code,
+        Err(e) if e.is_output_closed() => 2,
+        Err(e) => {
+            eprintln!("sort: {}", e);
+            2
+        }
+    };
+
+    if show_stats {
+        stats.report();
+    }
+
+    exit(code)
+}
+
+/// Run the mode of the payload, returns the exit status
+fn run(payload: Payload, stats: &Arc<Stats>) -> Result<i32, SortError> {
+    match payload.mode {
+        Mode::Sort => sort(payload, stats).map(|_| 0),
+        Mode::Merge => merge(payload, stats).map(|_| 0),
+        Mode::Check { quiet } => {
+            let start = Instant::now();
+            let sorted = check(payload, quiet);
+            stats.phase("check", start);
+
+            sorted.map(|sorted| if sorted { 0 } else { 1 })
+        }
+    }
+}
+
+/// Name of the input, for messages
+fn input_name<'a>(input_arg: &InputArg<&'a str>) -> &'a str {
+    match input_arg {
+        InputArg::Stdin => "-",
+        InputArg::File(filename) => filename,
+    }
+}
+
+/// Parse a buffer size: a number with an optional unit suffix, in KiB if there is none
+///
+/// Suffixes are `b` for bytes, `K`, `M`, `G`, `T`, `P`, `E` for powers of 1024, and `%` for a
+/// percentage of physical memory.
+fn parse_size(arg: &str) -> Option<usize> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, suffix) = arg.split_at(split);
+    let number: u64 = number.parse().ok()?;
+
+    let size = match suffix {
+        "%" => physical_memory()?.checked_mul(number)? / 100,
+        "b" => number,
+        "" | "k" | "K" => number.checked_mul(1 << 10)?,
+        "m" | "M" => number.checked_mul(1 << 20)?,
+        "g" | "G" => number.checked_mul(1 << 30)?,
+        "t" | "T" => number.checked_mul(1 << 40)?,
+        "p" | "P" => number.checked_mul(1 << 50)?,
+        "e" | "E" => number.checked_mul(1 << 60)?,
+        _ => return None,
+    };
+
+    // sizes beyond the address space are as good as unlimited
+    Some(usize::try_from(size).unwrap_or(usize::MAX))
+}
+
+/// Physical memory in bytes, or the memory limit of our cgroup when that is lower
+fn physical_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let total = line.split_whitespace().nth(1)?.parse::<u64>().ok()? << 10;
+
+    // cgroup v2 limit, "max" when unlimited
+    let limit = std::fs::read_to_string("/sys/fs/cgroup/memory.max")
+        .ok()
+        .and_then(|limit| limit.trim().parse::<u64>().ok());
+
+    Some(limit.map_or(total, |limit| limit.min(total)))
+}
+
+/// `sort --merge` handler
+///
+/// Inputs are opened at most `batch_size` at a time. When there are more inputs, they are merged
+/// in multiple passes through temp files. Groups of inputs are only opened by the job merging
+/// them, so queued groups hold no open files.
+fn merge(payload: Payload, stats: &Arc<Stats>) -> Result<(), SortError> {
+    let batch_size = payload.batch_size.get();
+    let spill = Arc::new(Spill::new(&payload));
+    let compare = Arc::new(payload.comparator);
+    let delimiter = payload.delimiter;
+    let start = Instant::now();
+
+    let chunks = if payload.inputs.len() > batch_size {
+        // first pass, merge groups of inputs into temp files
+        let merged = Arc::new(Mutex::new(Ok(vec![])));
+        let mut executor = ThreadPool::new(payload.sort_threads);
+
+        for (group_index, group) in payload.inputs.chunks(batch_size).enumerate() {
+            let group = group
+                .iter()
+                .map(|input_arg| match input_arg {
+                    InputArg::Stdin => InputArg::Stdin,
+                    InputArg::File(filename) => InputArg::File(filename.to_string()),
+                })
+                .collect();
+
+            executor.submit(SortJob::MergeInputs(
+                group,
+                group_index * batch_size,
+                compare.clone(),
+                merged.clone(),
+                spill.clone(),
+                stats.clone(),
+            ));
+        }
+
+        executor.finish();
+        let chunks = take_chunks(&merged)?;
+        reduce_chunks(
+            chunks,
+            batch_size,
+            &compare,
+            payload.sort_threads,
+            &spill,
+            stats,
+        )?
+    } else {
+        payload
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(seq, input_arg)| open_sorted(input_arg, delimiter, seq, compare.clone()))
+            .collect::<Result<_, _>>()?
+    };
+    let start = stats.phase("merge", start);
+
+    write_output(
+        chunks,
+        &compare,
+        &payload.output,
+        &payload.inputs,
+        payload.delimiter,
+        payload.debug,
+    )?;
+    stats.phase("output", start);
+
+    Ok(())
+}
+
+/// `sort --check` handler, returns whether the input is sorted
+///
+/// Lines are streamed through the comparator one by one, so inputs of any size are checked in
+/// constant memory.
+fn check(payload: Payload, quiet: bool) -> Result<bool, SortError> {
+    let input_arg = &payload.inputs[0];
+    let name = input_name(input_arg);
+    let read_failed = |e| SortError::Read(name.to_string(), e);
+
+    let input = Input::try_from(input_arg).map_err(read_failed)?;
+
+    let compare = &payload.comparator;
+    // for unique sorts, equal lines are out of order too
+    let disorder = if compare.unique {
+        |ordering| ordering != Ordering::Less
+    } else {
+        |ordering| ordering == Ordering::Greater
+    };
+
+    let mut prev: Option<Vec<u8>> = None;
+    for (index, line) in input.into_bufread().split(payload.delimiter).enumerate() {
+        let line = line.map_err(read_failed)?;
+
+        if let Some(prev) = &prev {
+            if disorder(compare.compare(prev, &line)) {
+                if !quiet {
+                    eprintln!(
+                        "sort: {}:{}: disorder: {}",
+                        name,
+                        index + 1,
+                        String::from_utf8_lossy(&line)
+                    );
+                }
+                return Ok(false);
+            }
+        }
+
+        prev = Some(line);
+    }
+
+    Ok(true)
+}
+
+/// Lines of all inputs, opened one after another
+fn read_lines<'a>(
+    inputs: &'a [InputArg<&'a str>],
+    delimiter: u8,
+) -> impl Iterator<Item = Result<Line, SortError>> + 'a {
+    inputs.iter().flat_map(move |input_arg| {
+        let name = input_name(input_arg);
+        let lines: Box<dyn Iterator<Item = _>> = match Input::try_from(input_arg) {
+            Ok(input) => Box::new(input.into_bufread().split(delimiter).map(move |line| {
+                line.map(Vec::into_boxed_slice) // save bytes by dropping cap field
+                    .map_err(|e| SortError::Read(name.to_string(), e))
+            })),
+            Err(e) => Box::new(std::iter::once(Err(SortError::Read(name.to_string(), e)))),
+        };
+        lines
+    })
+}
+
+/// Map all inputs into memory, if they are all regular files fitting in `buffer_size`
+fn map_inputs(inputs: &[InputArg<&str>], buffer_size: usize) -> Option<Vec<Mmap>> {
+    let mut total = 0;
+    let mut maps = vec![];
+
+    for input_arg in inputs {
+        let file = match input_arg {
+            InputArg::File(filename) => File::open(filename).ok()?,
+            InputArg::Stdin => return None,
+        };
+        let metadata = file.metadata().ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        total += metadata.len() as usize;
+        if total > buffer_size {
+            return None;
+        }
+
+        // empty files cannot be mapped, and have no lines anyway
+        if metadata.len() > 0 {
+            maps.push(unsafe { MmapOptions::new().map(&file).ok()? });
+        }
+    }
+
+    Some(maps)
+}
+
+/// `sort` handler for inputs mapped into memory
+///
+/// Lines are slices into the maps, so nothing is copied. Parts of the lines are sorted
+/// concurrently, after which a stable sort merges these sorted runs.
+fn sort_mapped(
+    payload: &Payload,
+    compare: &Comparator,
+    mut lines: Vec<&[u8]>,
+    stats: &Stats,
+) -> Result<(), SortError> {
+    let start = Instant::now();
+
+    let threads = payload.sort_threads.get() as usize;
+    let part = lines.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        for part in lines.chunks_mut(part) {
+            scope.spawn(move || compare.sort_slice(part));
+            stats.chunks_sorted.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+    });
+
+    // merge the sorted runs, equal lines keep the order of the runs
+    lines.sort_by(|a, b| compare.compare(a, b));
+    if compare.unique {
+        lines.dedup_by(|a, b| compare.compare(a, b) == Ordering::Equal);
+    }
+    let start = stats.phase("sort", start);
+
+    let mut writer = LineWriter::open(
+        &payload.output,
+        &payload.inputs,
+        payload.delimiter,
+        payload.debug,
+    )?;
+    for line in lines {
+        writer.write(line, compare)?;
+    }
+    writer.finish()?;
+    stats.phase("output", start);
+
+    Ok(())
+}
+
+/// `sort` handler
+///
+/// On failure, the jobs in flight are finished before returning, so all temp files are removed.
+fn sort(payload: Payload, stats: &Arc<Stats>) -> Result<(), SortError> {
+    let buffer_size = payload.buffer_size.map(|b| b.get()).unwrap_or(usize::MAX);
+
+    // fast path, sort slices of the inputs mapped into memory. Not when writing to one of the
+    // inputs, the lines must not be slices of a file that is being replaced
+    let start = Instant::now();
+    let maps = if output_is_input(&payload.output, &payload.inputs) {
+        None
+    } else {
+        map_inputs(&payload.inputs, buffer_size)
+    };
+    if let Some(maps) = maps {
+        let delimiter = payload.delimiter;
+        let lines: Vec<&[u8]> = maps
+            .iter()
+            .flat_map(|map| {
+                // a delimiter at the end does not start another line
+                let data = match map.last() {
+                    Some(c) if *c == delimiter => &map[..map.len() - 1],
+                    _ => &map[..],
+                };
+                data.split(move |c| *c == delimiter)
+            })
+            .collect();
+
+        let mapped: usize = maps.iter().map(|map| map.len()).sum();
+        if mapped + lines.len() * std::mem::size_of::<&[u8]>() <= buffer_size {
+            stats.phase("read", start);
+            return sort_mapped(&payload, &payload.comparator, lines, stats);
+        }
+    }
+
+    let mut line_iter = read_lines(&payload.inputs, payload.delimiter);
+
+    let batch_size = payload.batch_size.get();
+
+    let mut executor = ThreadPool::new(payload.sort_threads);
+    let spill = Arc::new(Spill::new(&payload));
+    let compare = Arc::new(payload.comparator);
+
+    let mut exhausted = false;
+    // memory held by lines that are not yet flushed to temp files
+    let mut resident: usize = 0;
+    let mut batches = 0;
+    let mut seq = 0;
+    let chunks = Arc::new(Mutex::new(Ok(vec![])));
+    let start = Instant::now();
+    // line that did not fit in the previous chunk
+    let mut held = None;
+
+    while !exhausted {
+        if chunks.lock().unwrap().is_err() {
+            break; // a job failed, reported below
+        }
+
+        let mut lines = vec![];
+
+        loop {
+            let line = match held.take().map(Ok).or_else(|| line_iter.next()) {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    executor.finish();
+                    return Err(e);
+                }
+                None => {
+                    exhausted = true;
+                    break;
+                }
+            };
+            let cost = line_cost(&line);
+
+            // stop short of the limit, but always take a line to make progress
+            let full = resident.saturating_add(cost) > buffer_size && !lines.is_empty();
+            if lines.len() == CHUNK_LINES || full {
+                held = Some(line);
+                break;
+            }
+
+            resident += cost;
+            lines.push(line);
+        }
+
+        // flush when near the limit, sorts and merges need some headroom for their buffers
+        let full = lines.len() < CHUNK_LINES || resident > buffer_size / 8 * 7;
+
+        executor.submit(SortJob::Sort(
+            lines,
+            seq,
+            compare.clone(),
+            chunks.clone(),
+            stats.clone(),
+        ));
+        batches += 1;
+        seq += 1;
+
+        if !exhausted && (full || batches > N_WAY_MERGE) {
             batches = 0;
-            let mut batch = vec![];
+
+            if full {
+                // wait for the sorts in flight, so their chunks are flushed too
+                executor.finish();
+                executor = ThreadPool::new(payload.sort_threads);
+            }
+
+            let merge_job = |batch| {
+                if full {
+                    SortJob::MergeFlush(
+                        batch,
+                        compare.clone(),
+                        chunks.clone(),
+                        spill.clone(),
+                        stats.clone(),
+                    )
+                } else {
+                    SortJob::Merge(batch, compare.clone(), chunks.clone(), stats.clone())
+                }
+            };
+
             let jobs = {
                 let mut lock = chunks.lock().unwrap();
-                let all = std::mem::replace(lock.deref_mut(), vec![]);
-
-                let mut jobs = all
+                let all = match lock.deref_mut() {
+                    Ok(all) => std::mem::take(all),
+                    Err(_) => vec![], // a job failed, reported below
+                };
+                // when approaching the limit, all chunks in memory are flushed
+                let (mut pending, done): (Vec<_>, Vec<_>) = all
                     .into_iter()
-                    .flat_map(|chunk| {
-                        if chunk.is_flushed() || chunk.is_merged() {
-                            lock.push(chunk);
-                        } else {
-                            batch.push(chunk);
-                            if batch.len() == N_WAY_MERGE {
-                                let payload = std::mem::replace(&mut batch, vec![]);
-                                if bytes > buffer_size {
-                                    return Some(SortJob::MergeFlush(payload, chunks.clone(), now));
-                                } else {
-                                    return Some(SortJob::Merge(payload, chunks.clone(), now));
-                                }
-                            }
-                        }
-
-                        None
-                    })
-                    .collect::<Vec<_>>();
+                    .partition(|chunk| !chunk.is_flushed() && (full || !chunk.is_merged()));
+                if let Ok(all) = lock.deref_mut() {
+                    *all = done;
+                }
 
-                if !batch.is_empty() {
-                    if bytes > buffer_size {
-                        jobs.push(SortJob::MergeFlush(batch, chunks.clone(), now));
-                    } else {
-                        jobs.push(SortJob::Merge(batch, chunks.clone(), now));
+                // only merge chunks of consecutive input batches, so equal lines keep their order
+                pending.sort_by_key(|chunk| chunk.seq);
+
+                let mut jobs = vec![];
+                let mut batch: Vec<SortedChunk> = vec![];
+                for chunk in pending {
+                    let consecutive = batch
+                        .last()
+                        .map(|last| last.seq + 1 == chunk.seq)
+                        .unwrap_or(true);
+                    if !consecutive || batch.len() == N_WAY_MERGE {
+                        jobs.push(merge_job(std::mem::take(&mut batch)));
                     }
+                    batch.push(chunk);
+                }
+
+                if !batch.is_empty() {
+                    jobs.push(merge_job(batch));
                 }
 
                 jobs
             }; // drop chunks write lock
 
             // this could block if all workers are busy
-            jobs.into_iter().for_each(|job| {
-                eprintln!(
-                    "{:>5} main - {} request",
-                    Instant::now().duration_since(now).as_millis(),
-                    match job {
-                        SortJob::MergeFlush(..) => "merge_flush",
-                        SortJob::Merge(..) => "merge",
-                        _ => unreachable!(),
-                    }
-                );
-                executor.submit(job)
-            });
+            jobs.into_iter().for_each(|job| executor.submit(job));
 
-            bytes = 0;
+            if full {
+                // memory is only released once the chunks are written
+                executor.finish();
+                executor = ThreadPool::new(payload.sort_threads);
+                resident = 0;
+            }
         }
     }
 
-    eprintln!(
-        "{:>5} main - await thread pool",
-        Instant::now().duration_since(now).as_millis()
-    );
+    let start = stats.phase("read", start);
+
     executor.finish();
+    let start = stats.phase("sort", start);
+
+    let chunks = take_chunks(&chunks)?;
+    let chunks = reduce_chunks(
+        chunks,
+        batch_size,
+        &compare,
+        payload.sort_threads,
+        &spill,
+        stats,
+    )?;
+    let chunks = merge_parallel(chunks, &compare, payload.sort_threads, stats)?;
+    let start = stats.phase("merge", start);
+
+    write_output(
+        chunks,
+        &compare,
+        &payload.output,
+        &payload.inputs,
+        payload.delimiter,
+        payload.debug,
+    )?;
+    stats.phase("output", start);
+
+    Ok(())
+}
 
-    eprintln!(
-        "{:>5} main - done, merging {} chunks",
-        Instant::now().duration_since(now).as_millis(),
-        chunks.lock().unwrap().len()
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comparator(keys: &[&str], separator: Option<u8>) -> Comparator {
+        let keys = keys
+            .iter()
+            .map(|k| KeySpec::parse(k, KeyOptions::default()).unwrap())
+            .collect();
+        Comparator {
+            keys,
+            separator,
+            last_resort: Some(KeyOptions::default()),
+            unique: false,
+        }
+    }
 
-    let stdout = io::stdout();
-    let stdout = stdout.lock();
-    let mut stdout = BufWriter::new(stdout);
-    let mut chunks: BinaryHeap<_> = chunks.lock().unwrap().drain(..).collect();
+    #[test]
+    fn test_parse_key() {
+        let key = KeySpec::parse("2.3b,4r", KeyOptions::default()).unwrap();
+        assert_eq!(key.start, (1, 2));
+        assert_eq!(key.end, Some((3, 0)));
+        assert!(key.options.blanks_start);
+        assert!(!key.options.blanks_end);
+        assert!(key.options.reverse);
+
+        assert!(KeySpec::parse("0", KeyOptions::default()).is_err());
+        assert!(KeySpec::parse("1.0", KeyOptions::default()).is_err());
+        assert!(KeySpec::parse("1,0", KeyOptions::default()).is_err());
+        assert!(KeySpec::parse("1x", KeyOptions::default()).is_err());
+        assert!(KeySpec::parse("", KeyOptions::default()).is_err());
+    }
 
-    while let Some(mut chunk) = chunks.pop() {
-        let exhausted = match chunks.peek().and_then(|c| c.peek()) {
-            Some(limit) => chunk.drain_until(&mut stdout, limit),
-            None => {
-                chunk.drain(&mut stdout);
-                true
-            }
+    #[test]
+    fn test_key_inherits_global() {
+        let global = KeyOptions {
+            dictionary: true,
+            ..KeyOptions::default()
+        };
+        assert_eq!(KeySpec::parse("1", global).unwrap().options, global);
+        assert!(!KeySpec::parse("1r", global).unwrap().options.dictionary);
+    }
+
+    #[test]
+    fn test_extract_blank_fields() {
+        let line = b"  foo bar\tbaz";
+        let extract = |spec| {
+            let key = KeySpec::parse(spec, KeyOptions::default()).unwrap();
+            key.extract(line, None).to_vec()
+        };
+
+        assert_eq!(extract("1"), b"  foo bar\tbaz");
+        assert_eq!(extract("1,1"), b"  foo");
+        assert_eq!(extract("2,2"), b" bar");
+        assert_eq!(extract("2b,2"), b"bar");
+        assert_eq!(extract("2.2b,2.3"), b"a");
+        assert_eq!(extract("2.2b,2.3b"), b"ar");
+        assert_eq!(extract("3"), b"\tbaz");
+        assert_eq!(extract("4"), b"");
+    }
+
+    #[test]
+    fn test_extract_separator() {
+        let line = b"a,,ccc,d";
+        let extract = |spec| {
+            let key = KeySpec::parse(spec, KeyOptions::default()).unwrap();
+            key.extract(line, Some(b',')).to_vec()
+        };
+
+        assert_eq!(extract("1,1"), b"a");
+        assert_eq!(extract("2,2"), b"");
+        assert_eq!(extract("3,3"), b"ccc");
+        assert_eq!(extract("3.2,3.2"), b"c");
+        assert_eq!(extract("3,4"), b"ccc,d");
+        assert_eq!(extract("4"), b"d");
+        assert_eq!(extract("5"), b"");
+    }
+
+    #[test]
+    fn test_compare_keys() {
+        let compare = comparator(&["2,2", "1,1r"], Some(b'\t'));
+        assert_eq!(compare.compare(b"a\t1", b"b\t2"), Ordering::Less);
+        assert_eq!(compare.compare(b"a\t2", b"b\t2"), Ordering::Greater);
+        assert_eq!(compare.compare(b"a\t2", b"a\t2"), Ordering::Equal);
+
+        // keys are equal, last resort byte comparison
+        let compare = comparator(&["1,1"], None);
+        assert_eq!(compare.compare(b"a b", b"a c"), Ordering::Less);
+
+        let compare = comparator(&["1d"], None);
+        assert_eq!(compare.compare(b"a-c", b"ab"), Ordering::Greater);
+    }
+
+    fn sorted(order: SortOrder, lines: &[&str]) -> Vec<String> {
+        let options = KeyOptions {
+            order,
+            ..KeyOptions::default()
+        };
+        let mut lines = lines.to_vec();
+        lines.sort_by(|a, b| options.compare(a.as_bytes(), b.as_bytes()));
+        lines.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_numeric() {
+        assert_eq!(
+            sorted(
+                SortOrder::Numerical,
+                &[
+                    "10",
+                    "-5",
+                    "abc",
+                    "9.5",
+                    "-0.5",
+                    " 2",
+                    "0010.10",
+                    "100000000000000000000"
+                ]
+            ),
+            vec![
+                "-5",
+                "-0.5",
+                "abc",
+                " 2",
+                "9.5",
+                "10",
+                "0010.10",
+                "100000000000000000000"
+            ]
+        );
+        assert_eq!(compare_numeric(b"-0", b"0"), Ordering::Equal);
+        assert_eq!(compare_numeric(b"1.50", b"1.5"), Ordering::Equal);
+        assert_eq!(compare_numeric(b"-1.05", b"-1.5"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_general_numeric() {
+        assert_eq!(
+            sorted(
+                SortOrder::General,
+                &["1e3", "abc", "-inf", "NaN", "2.5E-1", "+7", "10"]
+            ),
+            vec!["abc", "NaN", "-inf", "2.5E-1", "+7", "10", "1e3"]
+        );
+        assert_eq!(parse_float(b" 12e"), Some(12.));
+        assert_eq!(parse_float(b"infinite"), Some(f64::INFINITY));
+        assert_eq!(parse_float(b"."), None);
+
+        // hexadecimal numbers, with a binary exponent
+        assert_eq!(
+            sorted(
+                SortOrder::General,
+                &["0x1", "0.5", "-0", "0x.8p-1", "-0X1P1"]
+            ),
+            vec!["-0X1P1", "-0", "0x.8p-1", "0.5", "0x1"]
+        );
+        assert_eq!(parse_float(b"0x1.8p3"), Some(12.));
+        assert_eq!(parse_float(b"-0xAp-1z"), Some(-5.));
+        assert_eq!(parse_float(b"0x10p"), Some(16.));
+        assert_eq!(parse_float(b"0xg"), Some(0.));
+        assert_eq!(
+            parse_float(b"0x1p99999999999999999999"),
+            Some(f64::INFINITY)
+        );
+        assert_eq!(parse_float(b"0x0p99999999999999999999"), Some(0.));
+    }
+
+    #[test]
+    fn test_human_numeric() {
+        assert_eq!(
+            sorted(
+                SortOrder::Human,
+                &["1G", "2K", "-1M", "500", "0", "1.5K", "-3", "3M"]
+            ),
+            vec!["-1M", "-3", "0", "500", "1.5K", "2K", "3M", "1G"]
+        );
+    }
+
+    #[test]
+    fn test_month() {
+        assert_eq!(
+            sorted(SortOrder::Month, &["dec", " Feb", "foo", "JANUARY"]),
+            vec!["foo", "JANUARY", " Feb", "dec"]
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(
+            sorted(
+                SortOrder::Version,
+                &[
+                    "a-1.10.tar.gz",
+                    "a-1.2.tar.gz",
+                    "a-1.2~rc1.tar.gz",
+                    ".hidden",
+                    "a-1.2.zip",
+                    "a-1.02.tar.gz"
+                ]
+            ),
+            vec![
+                ".hidden",
+                "a-1.2~rc1.tar.gz",
+                "a-1.02.tar.gz",
+                "a-1.2.tar.gz",
+                "a-1.2.zip",
+                "a-1.10.tar.gz"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_and_locale() {
+        let options = KeyOptions {
+            fold: true,
+            ..KeyOptions::default()
+        };
+        let mut lines = vec!["b", "_", "A", "a"];
+        lines.sort_by(|a, b| options.compare(a.as_bytes(), b.as_bytes()));
+        assert_eq!(lines, vec!["A", "a", "b", "_"]);
+
+        // folding applies to every order, regardless of the order of the options
+        let mut options = KeyOptions::default();
+        options.parse("fV", true).unwrap();
+        let mut lines = vec!["b1", "A2", "a1", "B2"];
+        lines.sort_by(|a, b| options.compare(a.as_bytes(), b.as_bytes()));
+        assert_eq!(lines, vec!["a1", "A2", "b1", "B2"]);
+        assert_eq!(
+            KeySpec::parse("1,1Vf", KeyOptions::default())
+                .unwrap()
+                .options,
+            options
+        );
+
+        assert_eq!(
+            sorted(SortOrder::Locale, &["b", "B", "a-c", "ab", "A"]),
+            vec!["A", "ab", "a-c", "b", "B"]
+        );
+    }
+
+    #[test]
+    fn test_incompatible_options() {
+        let mut options = KeyOptions::default();
+        assert!(options.parse("fn", true).is_ok());
+        assert_eq!(options.order, SortOrder::Numerical);
+        assert!(options.parse("g", true).is_err());
+        assert!(KeySpec::parse("1dM", KeyOptions::default()).is_err());
+    }
+
+    fn merged(compare: Comparator, chunks: &[&[&str]]) -> Vec<String> {
+        let compare = Arc::new(compare);
+        let chunks = chunks
+            .iter()
+            .enumerate()
+            .map(|(seq, lines)| {
+                let mut lines: Vec<Line> = lines.iter().map(|l| l.as_bytes().into()).collect();
+                compare.sort(&mut lines);
+                SortedChunk::new(lines, seq, compare.clone())
+            })
+            .collect();
+
+        let mut output = vec![];
+        merge_chunks(chunks, &compare, |line| {
+            output.push(String::from_utf8(line.into()).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_merge_stable() {
+        let mut compare = comparator(&["1,1"], None);
+        compare.last_resort = None;
+
+        assert_eq!(
+            merged(
+                compare,
+                &[&["b 1", "a 1", "b 2"], &["a 2", "b 3"], &["a 3"]]
+            ),
+            vec!["a 1", "a 2", "a 3", "b 1", "b 2", "b 3"]
+        );
+    }
+
+    #[test]
+    fn test_merge_unique() {
+        let mut compare = comparator(&["1,1"], None);
+        compare.last_resort = None;
+        compare.unique = true;
+
+        assert_eq!(
+            merged(
+                compare,
+                &[&["b 1", "a 1", "b 2"], &["a 2", "c 3"], &["a 3", "c 4"]]
+            ),
+            vec!["a 1", "b 1", "c 3"]
+        );
+    }
+
+    #[test]
+    fn test_merge_reverse() {
+        let mut compare = comparator(&["1n"], None);
+        compare.keys[0].options.reverse = true;
+
+        assert_eq!(
+            merged(compare, &[&["1", "10"], &["2", "3"], &[]]),
+            vec!["10", "3", "2", "1"]
+        );
+    }
+
+    #[test]
+    fn test_annotate() {
+        let annotated = |compare: &Comparator, line: &str| {
+            let mut out = vec![];
+            compare.annotate(line.as_bytes(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let mut compare = comparator(&["2,2n"], None);
+        compare.keys[0].options.order = SortOrder::Numerical;
+        assert_eq!(annotated(&compare, "a\t 12x"), " \t __\n______\n");
+        assert_eq!(annotated(&compare, "a"), " ^ no match for key\n_\n");
+
+        compare.last_resort = None;
+        assert_eq!(annotated(&compare, "a -1.5"), "  ____\n");
+    }
+
+    #[test]
+    fn test_read_zero_terminated() {
+        let read = Box::new(io::Cursor::new(b"a\nb\0c\0".to_vec()));
+        let chunk = SortedChunk::from_read(read, "-", b'\0', 0, Arc::new(comparator(&[], None)));
+        let lines: Vec<_> = chunk.map(|line| line.into_vec()).collect();
+
+        assert_eq!(lines, vec![b"a\nb".to_vec(), b"c".to_vec()]);
+    }
+
+    fn spill(compress_program: Option<&str>) -> Arc<Spill> {
+        Arc::new(Spill {
+            delimiter: b'\n',
+            temp_dirs: vec![],
+            next_dir: AtomicUsize::new(0),
+            compress_program: compress_program.map(String::from),
+        })
+    }
+
+    /// Merge the lines into a temp file and read them back
+    fn spilled(spill: &Arc<Spill>, lines: &[&str]) -> Result<Vec<String>, SortError> {
+        let compare = Arc::new(comparator(&[], None));
+        let lines = lines.iter().map(|l| l.as_bytes().into()).collect();
+        let chunk = SortedChunk::new(lines, 0, compare.clone());
+        let stats = Arc::new(Stats::default());
+
+        let mut chunk = run_merge_flush(vec![chunk], compare, spill.clone(), stats)?;
+        let lines = chunk
+            .by_ref()
+            .map(|line| String::from_utf8(line.into()).unwrap())
+            .collect();
+        chunk.check()?;
+        Ok(lines)
+    }
+
+    #[test]
+    fn test_spill_compressed() {
+        let lines: Vec<String> = (0..1000).map(|i| format!("{:05}", i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        let spill = spill(Some("gzip"));
+        assert_eq!(spilled(&spill, &lines).unwrap(), lines);
+
+        // the temp file holds the compressed lines
+        let (mut file, mut write) = spill.create().unwrap();
+        write.write_all(b"a\n").unwrap();
+        write.finish().unwrap();
+        let mut compressed = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut compressed).unwrap();
+        assert_eq!(&compressed[..2], b"\x1f\x8b");
+    }
+
+    #[test]
+    fn test_spill_compress_failure() {
+        // the compressor fails, either writing to it or its exit status fails the merge
+        let error = spilled(&spill(Some("false")), &["a"; 100_000]).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("write failed: temporary file: "));
+
+        // `cat -d` is not a decompressor
+        let error = spilled(&spill(Some("cat")), &["a", "b"]).unwrap_err();
+        assert!(error.to_string().contains("decompress program failed"));
+
+        // a compressor that exits early breaks the pipe, which is reported unlike a closed output
+        let error = spilled(&spill(Some("true")), &["a"; 100_000]).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("write failed: temporary file: "));
+        assert!(!error.is_output_closed());
+        let closed = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert!(SortError::Output("-".to_string(), closed).is_output_closed());
+
+        let error = spilled(&spill(Some("/nonexistent/gzip")), &["a"]).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("couldn't create process for /nonexistent/gzip: "));
+    }
+
+    #[test]
+    fn test_spill_temp_dirs() {
+        let spill = Spill {
+            delimiter: b'\n',
+            temp_dirs: vec![PathBuf::from("a"), PathBuf::from("b")],
+            next_dir: AtomicUsize::new(0),
+            compress_program: None,
+        };
+        let dirs: Vec<_> = (0..5).map(|_| spill.next_dir()).collect();
+        assert_eq!(dirs, ["a", "b", "a", "b", "a"].map(PathBuf::from));
+
+        let spill = Spill {
+            temp_dirs: vec![],
+            ..spill
         };
-        if !exhausted {
-            chunks.push(chunk);
+        assert_eq!(spill.next_dir(), std::env::temp_dir());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("10"), Some(10 << 10));
+        assert_eq!(parse_size("10b"), Some(10));
+        assert_eq!(parse_size("2K"), Some(2 << 10));
+        assert_eq!(parse_size("3M"), Some(3 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert!(parse_size("50%").unwrap() > 0);
+        assert_eq!(parse_size("1X"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+    }
+
+    #[test]
+    fn test_line_cost() {
+        let pointer = std::mem::size_of::<Line>();
+        assert_eq!(line_cost(b""), pointer);
+        assert_eq!(line_cost(b"a"), pointer + MIN_ALLOCATION);
+        assert_eq!(line_cost(&[b'a'; 100]), pointer + 112);
+    }
+
+    #[test]
+    fn test_merge_read_error() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
         }
+
+        let compare = Arc::new(comparator(&[], None));
+        let lines = vec![b"a".to_vec().into_boxed_slice()];
+        let chunks = vec![
+            SortedChunk::new(lines, 0, compare.clone()),
+            SortedChunk::from_read(Box::new(Broken), "broken", b'\n', 1, compare.clone()),
+        ];
+
+        let result = merge_chunks(chunks, &compare, |_| Ok(()));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "cannot read: broken: broken"
+        );
+    }
+
+    #[test]
+    fn test_random() {
+        let lines = ["a", "b", "a", "c", "b", "a"];
+        let shuffled = sorted(SortOrder::Random(42), &lines);
+        assert_eq!(shuffled, sorted(SortOrder::Random(42), &lines));
+
+        // equal keys are adjacent
+        let mut groups = shuffled.clone();
+        groups.dedup();
+        assert_eq!(groups.len(), 3);
+
+        // keys equal when folded are adjacent
+        let mut options = KeyOptions::default();
+        options.parse("fR", true).unwrap();
+        for seed in 0..20 {
+            options.order = SortOrder::Random(seed);
+            let mut lines = vec!["A", "a", "b", "B", "c", "C"];
+            lines.sort_by(|a, b| options.compare(a.as_bytes(), b.as_bytes()));
+            for pair in lines.chunks(2) {
+                assert!(pair[0].eq_ignore_ascii_case(pair[1]), "{:?}", lines);
+            }
+        }
+
+        let mut options = KeyOptions::default();
+        assert!(options.parse("dR", true).is_ok());
+        assert!(options.parse("n", true).is_err());
+    }
+
+    #[test]
+    fn test_merge_parallel() {
+        let mut compare = comparator(&["1,1"], None);
+        compare.last_resort = None;
+        compare.unique = true;
+        let compare = Arc::new(compare);
+
+        let chunks: Vec<_> = (0..4)
+            .map(|seq| {
+                let mut lines: Vec<Line> = (0..100)
+                    .map(|i| {
+                        format!("{:03} {}", (i * 7 + seq) % 50, seq)
+                            .into_bytes()
+                            .into()
+                    })
+                    .collect();
+                compare.sort(&mut lines);
+                SortedChunk::new(lines, seq, compare.clone())
+            })
+            .collect();
+
+        let threads = NonZeroU32::new(3).unwrap();
+        let stats = Arc::new(Stats::default());
+        let merged = merge_parallel(chunks, &compare, threads, &stats).unwrap();
+        assert_eq!(merged.len(), 1);
+
+        let lines: Vec<_> = merged
+            .into_iter()
+            .flat_map(|chunk| chunk.into_lines().unwrap())
+            .map(|line| String::from_utf8(line.into()).unwrap())
+            .collect();
+
+        // every chunk has all keys, the first chunk wins
+        let expected: Vec<_> = (0..50).map(|key| format!("{:03} 0", key)).collect();
+        assert_eq!(lines, expected);
+    }
+
+    fn payload<'a>(inputs: &[&'a str], output: OutputArg<&'a str>, mode: Mode) -> Payload<'a> {
+        Payload {
+            inputs: inputs.iter().map(|input| InputArg::File(*input)).collect(),
+            output,
+            mode,
+            debug: false,
+            comparator: comparator(&[], None),
+            batch_size: NonZeroUsize::new(DEFAULT_BATCH_SIZE).unwrap(),
+            buffer_size: None,
+            sort_threads: NonZeroU32::new(2).unwrap(),
+            delimiter: b'\n',
+            temp_dirs: vec![],
+            compress_program: None,
+        }
+    }
+
+    #[test]
+    fn test_destination_replaces_input() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (input, link, other) = (path("in"), path("link"), path("other"));
+        std::fs::write(&input, "b\na\n").unwrap();
+        std::fs::set_permissions(&input, std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::fs::hard_link(&input, &link).unwrap();
+
+        let inputs = [InputArg::File(input.as_str())];
+        let other = Destination::open(&OutputArg::File(&other, false), &inputs).unwrap();
+        assert!(other.replace.is_none());
+
+        // the output is the input through a hard link, the input stays readable while writing
+        let mut destination = Destination::open(&OutputArg::File(&link, false), &inputs).unwrap();
+        assert!(destination.replace.is_some());
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), "b\na\n");
+
+        destination.write.write_all(b"a\nb\n").unwrap();
+        destination.finish().unwrap();
+
+        // the link is replaced, keeping its permissions, the other name keeps the old content
+        assert_eq!(std::fs::read_to_string(&link).unwrap(), "a\nb\n");
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), "b\na\n");
+        let mode = std::fs::metadata(&link).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_sort_into_hard_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (input, output) = (path("h1"), path("h2"));
+
+        let lines: Vec<String> = (0..10_000)
+            .map(|i| format!("{}", (i * 7919) % 10_000))
+            .collect();
+        std::fs::write(&input, lines.join("\n") + "\n").unwrap();
+        std::fs::hard_link(&input, &output).unwrap();
+
+        let payload = payload(&[&input], OutputArg::File(&output, false), Mode::Sort);
+        assert_eq!(run(payload, &Arc::new(Stats::default())).unwrap(), 0);
+
+        let mut sorted = lines.clone();
+        sorted.sort();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            sorted.join("\n") + "\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&input).unwrap(),
+            lines.join("\n") + "\n"
+        );
+    }
+
+    #[test]
+    fn test_sort_into_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (input, output) = (path("sub/f"), path("sym"));
+        std::fs::write(&input, "b\nc\na\n").unwrap();
+        std::os::unix::fs::symlink("sub/f", &output).unwrap();
+
+        let payload = payload(&[&input], OutputArg::File(&output, false), Mode::Sort);
+        assert_eq!(run(payload, &Arc::new(Stats::default())).unwrap(), 0);
+
+        // the file the link points to is sorted, the link is kept
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), "a\nb\nc\n");
+        let metadata = std::fs::symlink_metadata(&output).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(std::fs::read_dir(path("sub")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_merge_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+        let inputs: Vec<_> = (0..5).map(|i| path(&format!("in{}", i))).collect();
+        for (i, input) in inputs.iter().enumerate() {
+            let lines: String = (0..4).map(|j| format!("{} {}\n", j, i)).collect();
+            std::fs::write(input, lines).unwrap();
+        }
+        let output = path("out");
+
+        // stable, so equal keys keep the input order across passes
+        let inputs: Vec<_> = inputs.iter().map(String::as_str).collect();
+        let mut payload = payload(&inputs, OutputArg::File(&output, false), Mode::Merge);
+        payload.batch_size = NonZeroUsize::new(2).unwrap();
+        payload.comparator = comparator(&["1,1"], None);
+        payload.comparator.last_resort = None;
+
+        let stats = Arc::new(Stats::default());
+        assert_eq!(run(payload, &stats).unwrap(), 0);
+        // 3 groups of inputs, then 2 groups of temp files
+        assert_eq!(stats.merges.load(AtomicOrdering::Relaxed), 5);
+
+        let expected: String = (0..4)
+            .flat_map(|j| (0..5).map(move |i| format!("{} {}\n", j, i)))
+            .collect();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_merge_missing_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in").to_str().unwrap().to_string();
+        std::fs::write(&input, "a\n").unwrap();
+        let missing = dir.path().join("missing").to_str().unwrap().to_string();
+
+        let mut payload = payload(&[&input, &input, &missing], OutputArg::Stdout, Mode::Merge);
+        payload.batch_size = NonZeroUsize::new(2).unwrap();
+
+        let stats = Arc::new(Stats::default());
+        let error = run(payload, &stats).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("cannot read: {}: ", missing)));
     }
 
-    eprintln!(
-        "{:>5} main - done",
-        Instant::now().duration_since(now).as_millis()
-    );
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in").to_str().unwrap().to_string();
+        let check = |content: &str, unique: bool| {
+            std::fs::write(&input, content).unwrap();
+            let mut payload = payload(&[&input], OutputArg::Stdout, Mode::Check { quiet: true });
+            payload.comparator.unique = unique;
+            run(payload, &Arc::new(Stats::default())).unwrap()
+        };
+
+        assert_eq!(check("a\nb\nb\n", false), 0);
+        assert_eq!(check("a\nc\nb\n", false), 1);
+        // for unique sorts, equal lines are a disorder too
+        assert_eq!(check("a\nb\nb\n", true), 1);
+        assert_eq!(check("", false), 0);
+    }
 }
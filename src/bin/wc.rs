@@ -8,368 +8,1210 @@
 //! This executable counts chunks of bytes in parallel, taking care of proper multibyte char
 //! boundaries. Special care is taken to not exhaust memory when processing huge single-line files.
 //!
+//! Invalid UTF-8 sequences are counted as bytes, but not as chars.
+//!
+//! Chunks are counted separately, keeping the state at their boundaries: words and lines that may
+//! continue into the next chunk, and whether the newline at the end of the chunk was present. The
+//! chunks of an input are merged in order, matching the counts of a sequential pass exactly.
+//!
 //! Todo:
-//!  - print info for each input, not just grand total
+//!  - unassigned code points are printable word chars, glibc treats them as unprintable
 
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::io::{BufReader, Read};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
 use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
 use std::process::exit;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
 
 use coreutils::chunks::{ChunkedItem, ChunkedReader};
 use coreutils::executor::{Job, ThreadPool};
 use coreutils::io::{Input, InputArg};
-use coreutils::util::print_help_and_exit;
 
-const USAGE: &str = "cat [OPTION]... [FILE]... : concatenate FILE(s) to standard output";
+use clap::{App, Arg};
+use unicode_width::UnicodeWidthChar;
+
+const USAGE: &str = "Print newline, word, and byte counts for each FILE";
 
 /// Process chunks of 1MB each
 const CHUNK_SIZE: usize = 1 << 20;
 
-// https://tools.ietf.org/html/rfc3629
-static UTF8_CHAR_WIDTH: [u8; 256] = [
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, // 0x1F
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, // 0x3F
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, // 0x5F
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, // 0x7F
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, // 0x9F
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, // 0xBF
-    0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
-    2, // 0xDF
-    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, // 0xEF
-    4, 4, 4, 4, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // 0xFF
-];
-
-/// Stream bytes into chars
-struct BytesToChars<I: Iterator<Item = u8>> {
-    bytes: I,
+/// Count newlines
+///
+/// Bytes are compared in fixed size blocks, counting matches in a byte per lane, which compiles to
+/// vector instructions. The lanes are summed before they can overflow.
+fn count_newlines(data: &[u8]) -> u64 {
+    const LANES: usize = 32;
+
+    data.chunks(LANES * u8::MAX as usize)
+        .map(|block| {
+            let mut lanes = [0u8; LANES];
+            let mut blocks = block.chunks_exact(LANES);
+            for bytes in &mut blocks {
+                lanes
+                    .iter_mut()
+                    .zip(bytes)
+                    .for_each(|(lane, b)| *lane += (*b == b'\n') as u8);
+            }
+
+            let remainder = blocks.remainder().iter().filter(|b| **b == b'\n');
+            lanes.iter().map(|n| *n as u64).sum::<u64>() + remainder.count() as u64
+        })
+        .sum()
 }
 
-/// Unicode point, could be invalid
-struct UncheckedChar([u8; 4], usize);
+/// Count bytes and newlines of the input remaining after a chunk bailed
+fn count_bytes_read<R: Read>(data: Vec<u8>, mut read: R) -> io::Result<(u64, u64)> {
+    let mut bytes = data.len() as u64;
+    let mut lines = count_newlines(&data);
 
-impl<I: Iterator<Item = u8>> Iterator for BytesToChars<I> {
-    type Item = UncheckedChar;
+    let mut buf = data;
+    buf.resize(CHUNK_SIZE, 0);
+    loop {
+        let n = read.read(&mut buf)?;
+        if n == 0 {
+            return Ok((bytes, lines));
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = [0u8; 4];
+        bytes += n as u64;
+        lines += count_newlines(&buf[..n]);
+    }
+}
 
-        buf[0] = match self.bytes.next() {
-            None => return None,
-            Some(b) => b,
-        };
+/// Chars delimiting words
+///
+/// Like GNU wc this includes the no-break spaces, but not the line and paragraph separators.
+fn is_word_separator(c: char) -> bool {
+    match c {
+        '\u{85}' | '\u{2028}' | '\u{2029}' => false,
+        '\u{2060}' => true,
+        c => c.is_whitespace(),
+    }
+}
+
+/// Chars taking up space on the terminal, control chars and line separators are not printable
+fn is_printable(c: char) -> bool {
+    !c.is_control() && c != '\u{2028}' && c != '\u{2029}'
+}
+
+/// Next tab stop after a column
+fn tab_stop(column: u64) -> u64 {
+    column + 8 - column % 8
+}
 
-        let len = UTF8_CHAR_WIDTH[buf[0] as usize] as usize;
-        if len == 0 {
-            panic!("invalid unicode");
+/// Piece of a line, measuring its display width
+///
+/// The width depends on the column the piece starts at, but only up to its first tab. After that
+/// tab, the line is at a tab stop, so the rest is as wide as if it started at column zero.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct Segment {
+    /// width before the first tab
+    head: u64,
+    /// width after the first tab, if any
+    tail: Option<u64>,
+}
+
+impl Segment {
+    const TAB: Segment = Segment {
+        head: 0,
+        tail: Some(0),
+    };
+
+    fn push(&mut self, width: u64) {
+        match &mut self.tail {
+            None => self.head += width,
+            Some(tail) => *tail += width,
         }
+    }
 
-        for b in buf.iter_mut().take(len).skip(1) {
-            *b = self.bytes.next().expect("incomplete unicode char");
+    /// Column at the end of this segment, when starting at `column`
+    fn end(&self, column: u64) -> u64 {
+        match self.tail {
+            None => column + self.head,
+            Some(tail) => tab_stop(column + self.head) + tail,
         }
+    }
 
-        Some(UncheckedChar(buf, len))
+    /// This segment followed by the next one
+    fn then(self, next: Segment) -> Segment {
+        match (self.tail, next.tail) {
+            (None, _) => Segment {
+                head: self.head + next.head,
+                tail: next.tail,
+            },
+            (Some(tail), None) => Segment {
+                head: self.head,
+                tail: Some(tail + next.head),
+            },
+            (Some(tail), Some(next_tail)) => Segment {
+                head: self.head,
+                tail: Some(tab_stop(tail + next.head) + next_tail),
+            },
+        }
     }
 }
 
-/// Count bytes and newlines
-fn count_bytes<I: Iterator<Item = u8>>(input: I) -> (u64, u64) {
-    let mut bytes = 0;
-    let mut lines = 0;
+/// Running count of all `wc` quantities of a part of the input
+///
+/// Besides the counts, the state at the boundaries of the part is kept: the words and line pieces
+/// that may continue into the neighbouring parts. This allows parts that are counted in parallel
+/// to be merged into exactly the counts of the whole input.
+#[derive(Default, Clone, Debug, PartialEq)]
+struct Counter {
+    bytes: u64,
+    chars: u64,
+    words: u64,
+    lines: u64,
+    /// max display width of the lines that start and end within this part
+    max_line_length: u64,
+
+    /// the part contains a line break (newline, carriage return or form feed)
+    line_break: bool,
+    /// line piece before the first line break
+    first_line: Segment,
+    /// line piece after the last line break, or the whole part without line breaks
+    last_line: Segment,
+
+    /// whether the first and last chars that start or end words are part of a word, unprintable
+    /// chars and invalid bytes do neither
+    first_in_word: Option<bool>,
+    last_in_word: Option<bool>,
+}
+
+impl Counter {
+    /// Count a slice of input, returning the number of bytes consumed
+    ///
+    /// A multibyte char that is cut off at the end of the slice is not consumed, so it can be
+    /// completed by the next slice. If this is the `last` slice, it is counted as invalid bytes.
+    fn update(&mut self, bytes: &[u8], last: bool) -> usize {
+        let mut pos = 0;
+
+        while pos < bytes.len() {
+            let (valid, invalid) = match std::str::from_utf8(&bytes[pos..]) {
+                Ok(valid) => (valid, 0),
+                Err(e) => {
+                    let valid = std::str::from_utf8(&bytes[pos..pos + e.valid_up_to()]).unwrap();
+                    match e.error_len() {
+                        Some(len) => (valid, len),
+                        None if last => (valid, bytes.len() - pos - valid.len()),
+                        None => {
+                            valid.chars().for_each(|c| self.count_char(c));
+                            return pos + valid.len();
+                        }
+                    }
+                }
+            };
+
+            valid.chars().for_each(|c| self.count_char(c));
+
+            // invalid sequences are counted as bytes, but not as chars
+            self.bytes += invalid as u64;
 
-    for b in input {
-        bytes += 1;
-        if b == b'\n' {
-            lines += 1;
+            pos += valid.len() + invalid;
         }
+
+        pos
     }
 
-    // count the newline at the end of this chunk
-    bytes += 1;
-    lines += 1;
+    fn count_char(&mut self, c: char) {
+        self.bytes += c.len_utf8() as u64;
+        self.chars += 1;
 
-    (bytes, lines)
-}
+        match c {
+            '\n' | '\r' | '\x0c' => {
+                if c == '\n' {
+                    self.lines += 1;
+                }
+                self.break_line();
+            }
+            '\t' => self.last_line = self.last_line.then(Segment::TAB),
+            c if is_printable(c) => self.last_line.push(c.width().unwrap_or(0) as u64),
+            _ => (),
+        }
 
-/// Count all `wc` quantities
-fn count_all<I: Iterator<Item = UncheckedChar>>(input: I) -> (u64, u64, u64, u64, u64) {
-    let mut bytes = 0;
-    let mut chars = 0;
-    let mut lines = 0;
-    let mut words = 0;
-    let mut max_line_length = 0;
-
-    let mut line_length = 0;
-    let mut prev_was_whitespace = true;
-    let mut prev_was_newline = false;
-
-    for UncheckedChar(buf, len) in input {
-        bytes += len as u64;
-        chars += 1;
-
-        // only consider ASCII whitespace for now
-        if len == 1 && buf[0] >= 9 && buf[0] <= 14 {
-            if !prev_was_whitespace {
-                words += 1;
-                prev_was_whitespace = true;
+        if is_word_separator(c) {
+            self.first_in_word.get_or_insert(false);
+            self.last_in_word = Some(false);
+        } else if is_printable(c) {
+            if self.last_in_word != Some(true) {
+                self.words += 1;
             }
+            self.first_in_word.get_or_insert(true);
+            self.last_in_word = Some(true);
+        }
+    }
 
-            if buf[0] == 10 {
-                if line_length > max_line_length {
-                    max_line_length = line_length;
-                }
+    fn break_line(&mut self) {
+        let line = std::mem::take(&mut self.last_line);
+        if self.line_break {
+            self.max_line_length = self.max_line_length.max(line.end(0));
+        } else {
+            self.first_line = line;
+            self.line_break = true;
+        }
+    }
+
+    /// Counts of the delimiter that is stripped from the end of a chunk
+    fn delimiter() -> Self {
+        let mut counter = Self::default();
+        counter.count_char('\n');
+        counter
+    }
+
+    /// Merge the counts of the part that directly follows this one
+    fn merge(&mut self, next: &Counter) {
+        self.bytes += next.bytes;
+        self.chars += next.chars;
+        self.lines += next.lines;
 
-                lines += 1;
-                line_length = 0;
-                prev_was_newline = true;
-            } else {
-                line_length += 1;
-                prev_was_newline = false;
+        // a word that continues over the boundary was counted in both parts
+        self.words += next.words;
+        if self.last_in_word == Some(true) && next.first_in_word == Some(true) {
+            self.words -= 1;
+        }
+        self.first_in_word = self.first_in_word.or(next.first_in_word);
+        self.last_in_word = next.last_in_word.or(self.last_in_word);
+
+        match (self.line_break, next.line_break) {
+            (_, false) => self.last_line = self.last_line.then(next.last_line),
+            (false, true) => {
+                self.first_line = self.last_line.then(next.first_line);
+                self.last_line = next.last_line;
+                self.line_break = true;
+                self.max_line_length = next.max_line_length;
+            }
+            (true, true) => {
+                let line = self.last_line.then(next.first_line);
+                self.max_line_length = self
+                    .max_line_length
+                    .max(next.max_line_length)
+                    .max(line.end(0));
+                self.last_line = next.last_line;
             }
-        } else {
-            prev_was_whitespace = false;
-            prev_was_newline = false;
-            line_length += 1;
         }
     }
 
-    if !prev_was_newline {
-        chars += 1;
-        bytes += 1;
-        lines += 1;
+    /// Count the remainder of the input, streaming it in blocks
+    fn update_read<R: Read>(&mut self, data: Vec<u8>, mut read: R) -> io::Result<()> {
+        let mut buf = data;
+
+        loop {
+            let consumed = self.update(&buf, false);
+            buf.drain(..consumed);
+
+            let len = buf.len();
+            buf.resize(len + CHUNK_SIZE, 0);
+            let n = read.read(&mut buf[len..])?;
+            buf.truncate(len + n);
+
+            if n == 0 {
+                self.update(&buf, true);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Counts of the whole input, when this part starts at its beginning
+    fn finish(self) -> Counts {
+        let first_line = if self.line_break {
+            self.first_line.end(0)
+        } else {
+            0
+        };
+
+        Counts {
+            lines: self.lines,
+            words: self.words,
+            chars: self.chars,
+            bytes: self.bytes,
+            max_line_length: self
+                .max_line_length
+                .max(first_line)
+                .max(self.last_line.end(0)),
+        }
     }
+}
 
-    if !prev_was_whitespace {
-        words += 1;
+/// Counted quantities of an input, or a chunk of it
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    lines: u64,
+    words: u64,
+    chars: u64,
+    bytes: u64,
+    max_line_length: u64,
+}
+
+impl Counts {
+    /// Add the counts of another input
+    fn merge(&mut self, other: &Counts) {
+        self.lines += other.lines;
+        self.words += other.words;
+        self.chars += other.chars;
+        self.bytes += other.bytes;
+        self.max_line_length = self.max_line_length.max(other.max_line_length);
     }
 
-    (bytes, chars, words, lines, max_line_length)
+    /// The quantities, in the column order of `wc` output
+    fn values(&self) -> [u64; 5] {
+        [
+            self.lines,
+            self.words,
+            self.chars,
+            self.bytes,
+            self.max_line_length,
+        ]
+    }
 }
 
+/// Counts of a chunk, or the failure to read it, tagged with the index of its input and its
+/// position in that input
+type ChunkResult = (usize, usize, io::Result<Counter>);
+
 struct WcBytesJob {
     chunk: ChunkedItem<Box<dyn Read + Send>>,
+    /// the chunk was followed by a newline
+    delimited: bool,
 
-    bytes: Arc<AtomicU64>,
-    lines: Arc<AtomicU64>,
+    /// index of the input this chunk belongs to
+    index: usize,
+    /// position of the chunk in its input
+    seq: usize,
+    results: Sender<ChunkResult>,
 }
 
 impl Job for WcBytesJob {
     fn run(self) {
-        let (b, l) = match self.chunk {
-            ChunkedItem::Chunk(data) => count_bytes(data.into_iter()),
-            ChunkedItem::Bail(data, read) => {
-                let bytes_left = BufReader::new(read).bytes().map(|b| b.unwrap());
-                let bytes = data.into_iter().chain(bytes_left);
-                count_bytes(bytes)
-            }
+        let counts = match self.chunk {
+            ChunkedItem::Chunk(data) => Ok((data.len() as u64, count_newlines(&data))),
+            ChunkedItem::Bail(data, read) => count_bytes_read(data, read),
         };
 
-        self.bytes.fetch_add(b, Ordering::Relaxed);
-        self.lines.fetch_add(l, Ordering::Relaxed);
+        let delimited = self.delimited;
+        let result = counts.map(|(bytes, lines)| {
+            let mut counter = Counter {
+                bytes,
+                lines,
+                ..Counter::default()
+            };
+            if delimited {
+                counter.merge(&Counter::delimiter());
+            }
+            counter
+        });
+
+        self.results.send((self.index, self.seq, result)).unwrap();
     }
 }
 
 struct WcAllJob {
     chunk: ChunkedItem<Box<dyn Read + Send>>,
+    /// the chunk was followed by a newline
+    delimited: bool,
 
-    bytes: Arc<AtomicU64>,
-    chars: Arc<AtomicU64>,
-    words: Arc<AtomicU64>,
-    lines: Arc<AtomicU64>,
-    max_line_length: Arc<AtomicU64>,
+    /// index of the input this chunk belongs to
+    index: usize,
+    /// position of the chunk in its input
+    seq: usize,
+    results: Sender<ChunkResult>,
 }
 
 impl Job for WcAllJob {
     fn run(self) {
-        let (b, c, w, l, ll) = match self.chunk {
+        let mut counter = Counter::default();
+
+        let result = match self.chunk {
             ChunkedItem::Chunk(data) => {
-                let chars = BytesToChars {
-                    bytes: data.into_iter(),
-                };
-                count_all(chars)
-            }
-            ChunkedItem::Bail(data, read) => {
-                let bytes_left = BufReader::new(read).bytes().map(|b| b.unwrap());
-                let bytes = data.into_iter().chain(bytes_left);
-                let chars = BytesToChars { bytes };
-                count_all(chars)
+                counter.update(&data, true);
+                Ok(())
             }
+            ChunkedItem::Bail(data, read) => counter.update_read(data, read),
         };
+        if self.delimited {
+            counter.merge(&Counter::delimiter());
+        }
 
-        self.bytes.fetch_add(b, Ordering::Relaxed);
-        self.chars.fetch_add(c, Ordering::Relaxed);
-        self.words.fetch_add(w, Ordering::Relaxed);
-        self.lines.fetch_add(l, Ordering::Relaxed);
-        self.max_line_length.fetch_add(ll, Ordering::Relaxed);
+        let result = result.map(|_| counter);
+        self.results.send((self.index, self.seq, result)).unwrap();
     }
 }
 
 /// Parse arguments, run job, pass return code
 fn main() -> ! {
-    let mut args = std::env::args();
-    args.next(); // bin name
-
-    let mut has_stdin = false; // only take stdin once
-
-    let mut bytes = false;
-    let mut chars = false;
-    let mut words = false;
-    let mut lines = false;
-    let mut max_line_length = false;
-
-    let mut inputs: Vec<_> = args
-        .flat_map(|s| match s.as_ref() {
-            "-" if has_stdin => None,
-            "-" => {
-                has_stdin = true;
-                Some(InputArg::Stdin)
-            }
-            "-b" => {
-                bytes = true;
-                None
-            }
-            "-c" => {
-                chars = true;
-                None
+    let matches = App::new("wc")
+        .about(USAGE)
+        .arg(
+            Arg::with_name("bytes")
+                .short("c")
+                .long("bytes")
+                .help("print the byte counts"),
+        )
+        .arg(
+            Arg::with_name("chars")
+                .short("m")
+                .long("chars")
+                .help("print the character counts"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("print the counts and errors of each input as JSON"),
+        )
+        .arg(
+            Arg::with_name("lines")
+                .short("l")
+                .long("lines")
+                .help("print the newline counts"),
+        )
+        .arg(
+            Arg::with_name("files0-from")
+                .long("files0-from")
+                .value_name("F")
+                .help("read input from the files specified by NUL-terminated names in file F, use - for stdin")
+                .takes_value(true)
+                .conflicts_with("FILE"),
+        )
+        .arg(
+            Arg::with_name("max-line-length")
+                .short("L")
+                .long("max-line-length")
+                .help("print the maximum display width"),
+        )
+        .arg(
+            Arg::with_name("words")
+                .short("w")
+                .long("words")
+                .help("print the word counts"),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .help("input FILE(s), use - for stdin")
+                .multiple(true)
+                .index(1),
+        )
+        .get_matches();
+
+    let mut bytes = matches.is_present("bytes");
+    let chars = matches.is_present("chars");
+    let mut words = matches.is_present("words");
+    let mut lines = matches.is_present("lines");
+    let max_line_length = matches.is_present("max-line-length");
+
+    // default to `-lwc`
+    if !bytes && !chars && !words && !lines && !max_line_length {
+        lines = true;
+        words = true;
+        bytes = true;
+    }
+
+    let columns = [lines, words, chars, bytes, max_line_length];
+
+    let (inputs, width, show_names): (Box<dyn Iterator<Item = _>>, _, _) =
+        match matches.value_of("files0-from") {
+            Some(source) => {
+                let (names, regular) = match files0_from(source) {
+                    Ok(names) => names,
+                    Err(e) => {
+                        eprintln!("wc: cannot open '{}' for reading: {}", source, e);
+                        exit(1)
+                    }
+                };
+
+                if regular {
+                    // a list of names that does not change can be read up front, to align the columns
+                    let names: Vec<_> = names.collect();
+                    let width = number_width(&names, &columns);
+                    (Box::new(names.into_iter()), width, true)
+                } else {
+                    (Box::new(names), 1, true)
+                }
             }
-            "-w" => {
-                words = true;
-                None
+            None => {
+                let mut has_stdin = false; // only take stdin once
+                let mut inputs: Vec<_> = matches
+                    .values_of_os("FILE")
+                    .map(|values| {
+                        values
+                            .flat_map(|v| match v.to_str() {
+                                Some("-") if has_stdin => None,
+                                Some("-") => {
+                                    has_stdin = true;
+                                    Some(Ok(InputArg::Stdin))
+                                }
+                                _ => Some(Ok(InputArg::File(PathBuf::from(v)))),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                // only print names for inputs given on the command line
+                let show_names = !inputs.is_empty();
+                if inputs.is_empty() {
+                    inputs.push(Ok(InputArg::Stdin));
+                }
+
+                let width = number_width(&inputs, &columns);
+                (Box::new(inputs.into_iter()), width, show_names)
             }
-            "-l" => {
-                lines = true;
-                None
+        };
+
+    let format = if matches.is_present("json") {
+        Format::Json
+    } else {
+        Format::Text { width, show_names }
+    };
+
+    let mut report = Report::new(columns, format);
+    if chars || words || max_line_length {
+        wc_all(inputs, &mut report);
+    } else {
+        wc_bytes(inputs, lines, &mut report);
+    }
+
+    exit(if report.finish() { 1 } else { 0 })
+}
+
+/// Input name, or a message why the name is invalid
+type InputName = Result<InputArg<PathBuf>, String>;
+
+/// Stream the NUL-terminated input names of `--files0-from`
+///
+/// Also returns if the names are read from a regular file.
+fn files0_from(source: &str) -> io::Result<(impl Iterator<Item = InputName>, bool)> {
+    let source = source.to_string();
+    let (input_arg, metadata) = match source.as_ref() {
+        "-" => (InputArg::Stdin, fs::metadata("/dev/stdin")),
+        _ => (InputArg::File(&source), fs::metadata(&source)),
+    };
+    let input = Input::try_from(&input_arg)?;
+    let regular = metadata.map(|m| m.is_file()).unwrap_or(false);
+    let from_stdin = matches!(input_arg, InputArg::Stdin);
+
+    let mut names = input.into_bufread().split(b'\0');
+    let mut index = 0;
+    let mut done = false;
+
+    let iter = std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        index += 1;
+
+        let name = match names.next()? {
+            Ok(name) => name,
+            Err(e) => {
+                // stop at the first read error
+                done = true;
+                return Some(Err(format!("{}: read error: {}", source, e)));
             }
-            "-L" => {
-                max_line_length = true;
-                None
+        };
+
+        Some(match &name[..] {
+            b"" => Err(format!(
+                "{}:{}: invalid zero-length file name",
+                source, index
+            )),
+            b"-" if from_stdin => {
+                Err("when reading file names from stdin, no file name of '-' allowed".to_string())
             }
-            s if s.starts_with('-') => print_help_and_exit(USAGE),
-            _ => Some(InputArg::File(s)),
+            b"-" => Ok(InputArg::Stdin),
+            _ => Ok(InputArg::File(PathBuf::from(OsString::from_vec(name)))),
         })
-        .collect();
+    });
 
-    if inputs.is_empty() {
-        inputs.push(InputArg::Stdin);
-    }
+    Ok((iter, regular))
+}
 
-    if !bytes && !chars && !words && !lines && !max_line_length {
-        chars = true;
-        words = true;
-        lines = true;
+/// Open an input, or return its name and the message why it cannot be read
+fn open_input(input: InputName) -> Result<(InputArg<PathBuf>, Input), (Option<String>, String)> {
+    let input_arg = input.map_err(|e| (None, e))?;
+    match Input::try_from(&input_arg) {
+        Ok(input) => Ok((input_arg, input)),
+        Err(e) => Err((Some(display_name(&input_arg).into_owned()), e.to_string())),
     }
+}
 
-    if chars || words || max_line_length {
-        wc_all(&inputs, bytes, chars, words, lines, max_line_length);
-    } else {
-        wc_bytes(&inputs, bytes, lines);
+/// Size of a regular file, and whether that is all of its contents
+///
+/// Pseudo files in e.g. /proc and /sys report a size of zero or a full block. Like GNU wc, those
+/// are not trusted: the file is positioned near the end so only its last block has to be read.
+fn file_size(file: &mut File) -> Option<(u64, bool)> {
+    let metadata = file.metadata().ok().filter(|m| m.is_file())?;
+    let size = metadata.len();
+    if size % 4096 != 0 {
+        return Some((size, true));
     }
 
-    exit(0)
+    let skip = size - size % (metadata.blksize() + 1);
+    file.seek(SeekFrom::Start(skip)).ok()?;
+    Some((skip, false))
 }
 
 /// `wc` implementation, counting only bytes and/or lines
-fn wc_bytes(input_args: &[InputArg<String>], show_bytes: bool, show_lines: bool) {
-    let bytes_total = Arc::new(AtomicU64::new(0));
-    let lines_total = Arc::new(AtomicU64::new(0));
-
+///
+/// When no lines are counted, regular files are not read at all.
+fn wc_bytes<I: Iterator<Item = InputName>>(inputs: I, count_lines: bool, report: &mut Report) {
     let mut executor = ThreadPool::new(NonZeroU32::new(num_cpus::get() as u32).unwrap());
 
-    input_args
-        .iter()
-        .flat_map(|input_arg| {
-            Input::try_from(input_arg)
-                .map_err(|_| eprintln!("Unable to read {:?}", input_arg))
-                .ok()
-        })
-        .map(|input| input.into_read())
-        .for_each(|read| {
-            let reader = ChunkedReader::new(read, b'\n', CHUNK_SIZE);
-            reader.for_each(|chunk| {
-                let job = WcBytesJob {
-                    chunk: chunk.unwrap(),
-                    bytes: bytes_total.clone(),
-                    lines: lines_total.clone(),
+    for input in inputs {
+        let (input_arg, mut input) = match open_input(input) {
+            Ok(input) => input,
+            Err(error) => {
+                report.fail(error);
+                continue;
+            }
+        };
+
+        let index = report.begin(&input_arg);
+        let results = report.results();
+        let mut chunks = 0;
+
+        if let (false, Input::File(file)) = (count_lines, &mut input) {
+            if let Some((bytes, complete)) = file_size(file) {
+                let counter = Counter {
+                    bytes,
+                    ..Counter::default()
                 };
-                executor.submit(job);
-            });
-        });
+                results.send((index, chunks, Ok(counter))).unwrap();
+                chunks += 1;
+
+                if complete {
+                    report.end(index, chunks, None);
+                    continue;
+                }
+            }
+        }
+
+        let mut error = None;
+        let mut reader = ChunkedReader::new(input.into_read(), b'\n', CHUNK_SIZE);
+        while let Some(chunk) = reader.next() {
+            match chunk {
+                Ok(chunk) => executor.submit(WcBytesJob {
+                    chunk,
+                    delimited: reader.delimited(),
+                    index,
+                    seq: chunks,
+                    results: results.clone(),
+                }),
+                Err(e) => {
+                    error = Some(e);
+                    continue;
+                }
+            }
+            chunks += 1;
+        }
+
+        report.end(index, chunks, error);
+    }
 
     executor.finish();
+}
+
+/// `wc` implementation, counting all quantities
+fn wc_all<I: Iterator<Item = InputName>>(inputs: I, report: &mut Report) {
+    let mut executor = ThreadPool::new(NonZeroU32::new(num_cpus::get() as u32).unwrap());
+
+    for input in inputs {
+        let (input_arg, input) = match open_input(input) {
+            Ok(input) => input,
+            Err(error) => {
+                report.fail(error);
+                continue;
+            }
+        };
+
+        let index = report.begin(&input_arg);
+        let results = report.results();
+        let mut chunks = 0;
 
-    if show_lines {
-        print!("l:{} ", lines_total.load(Ordering::Relaxed));
+        let mut error = None;
+        let mut reader = ChunkedReader::new(input.into_read(), b'\n', CHUNK_SIZE);
+        while let Some(chunk) = reader.next() {
+            match chunk {
+                Ok(chunk) => executor.submit(WcAllJob {
+                    chunk,
+                    delimited: reader.delimited(),
+                    index,
+                    seq: chunks,
+                    results: results.clone(),
+                }),
+                Err(e) => {
+                    error = Some(e);
+                    continue;
+                }
+            }
+            chunks += 1;
+        }
+
+        report.end(index, chunks, error);
     }
-    if show_bytes {
-        print!("b:{} ", bytes_total.load(Ordering::Relaxed));
+
+    executor.finish();
+}
+
+/// Name of the input as printed in the output
+fn display_name(input_arg: &InputArg<PathBuf>) -> Cow<'_, str> {
+    match input_arg {
+        InputArg::Stdin => "-".into(),
+        InputArg::File(name) => name.to_string_lossy(),
     }
-    println!()
 }
 
-/// `wc` implementation, counting all quantities
-fn wc_all(
-    input_args: &[InputArg<String>],
-    show_bytes: bool,
-    show_chars: bool,
-    show_words: bool,
-    show_lines: bool,
-    show_max_line_length: bool,
-) {
-    let bytes_total = Arc::new(AtomicU64::new(0));
-    let chars_total = Arc::new(AtomicU64::new(0));
-    let words_total = Arc::new(AtomicU64::new(0));
-    let lines_total = Arc::new(AtomicU64::new(0));
-    let max_line_length = Arc::new(AtomicU64::new(0));
+/// Width of the output columns
+///
+/// Like GNU wc, this is the number of digits of the combined size of all regular files, and at
+/// least 7 when reading other kinds of input. A single quantity of a single input is not padded.
+fn number_width(inputs: &[InputName], columns: &[bool; 5]) -> usize {
+    if inputs.len() == 1 && columns.iter().filter(|c| **c).count() == 1 {
+        return 1;
+    }
 
-    let mut executor = ThreadPool::new(NonZeroU32::new(num_cpus::get() as u32).unwrap());
+    let mut minimum_width = 1;
+    let mut regular_total = 0;
+    for input_arg in inputs.iter().flatten() {
+        let metadata = match input_arg {
+            InputArg::Stdin => fs::metadata("/dev/stdin"),
+            InputArg::File(name) => fs::metadata(name),
+        };
+        match metadata {
+            Ok(metadata) if metadata.is_file() => regular_total += metadata.len(),
+            Ok(_) => minimum_width = 7,
+            Err(_) => (),
+        }
+    }
 
-    input_args
-        .iter()
-        .flat_map(|input_arg| {
-            Input::try_from(input_arg)
-                .map_err(|_| eprintln!("Unable to read {:?}", input_arg))
-                .ok()
-        })
-        .map(|input| input.into_read())
-        .for_each(|read| {
-            let reader = ChunkedReader::new(read, b'\n', CHUNK_SIZE);
-            reader.for_each(|chunk| {
-                let job = WcAllJob {
-                    chunk: chunk.unwrap(),
-                    bytes: bytes_total.clone(),
-                    chars: chars_total.clone(),
-                    words: words_total.clone(),
-                    lines: lines_total.clone(),
-                    max_line_length: max_line_length.clone(),
+    minimum_width.max(regular_total.to_string().len())
+}
+
+/// Result of counting a single input, as reported by all output formats
+struct InputResult {
+    /// name of the input, absent if the name itself is invalid
+    name: Option<String>,
+    /// counted quantities, absent if the input could not be opened
+    counts: Option<Counts>,
+    /// why the input could not be opened or read completely
+    error: Option<String>,
+}
+
+/// Input in the output queue, waiting for the results of its chunks
+struct Pending {
+    result: InputResult,
+    /// number of chunks, known when the input is read completely
+    chunks: Option<usize>,
+    /// chunk results by position, they may arrive in any order
+    parts: Vec<Option<Counter>>,
+    received: usize,
+}
+
+/// Output format of the results
+enum Format {
+    /// GNU `wc` columns, with the width of the counts
+    Text { width: usize, show_names: bool },
+    /// A JSON object with the results of all inputs and the total
+    Json,
+}
+
+/// Collects the results of all inputs, and prints them in order as soon as they are complete
+struct Report {
+    columns: [bool; 5],
+    format: Format,
+
+    /// inputs not printed yet, the front has index `printed`
+    queue: VecDeque<Pending>,
+    printed: usize,
+    results: Sender<ChunkResult>,
+    receiver: Receiver<ChunkResult>,
+
+    total: Counts,
+    failed: bool,
+}
+
+impl Report {
+    fn new(columns: [bool; 5], format: Format) -> Self {
+        let (results, receiver) = channel();
+
+        Self {
+            columns,
+            format,
+            queue: VecDeque::new(),
+            printed: 0,
+            results,
+            receiver,
+            total: Counts::default(),
+            failed: false,
+        }
+    }
+
+    /// Channel for the chunk results of the jobs
+    fn results(&self) -> Sender<ChunkResult> {
+        self.results.clone()
+    }
+
+    /// Queue an input that will be counted, returns its index
+    fn begin(&mut self, input_arg: &InputArg<PathBuf>) -> usize {
+        self.queue.push_back(Pending {
+            result: InputResult {
+                name: Some(display_name(input_arg).into_owned()),
+                counts: Some(Counts::default()),
+                error: None,
+            },
+            chunks: None,
+            parts: vec![],
+            received: 0,
+        });
+
+        self.printed + self.queue.len() - 1
+    }
+
+    /// Mark an input as read completely, after submitting its chunks
+    fn end(&mut self, index: usize, chunks: usize, error: Option<io::Error>) {
+        let pending = &mut self.queue[index - self.printed];
+        pending.chunks = Some(chunks);
+        if let Some(e) = error {
+            pending.result.error = Some(e.to_string());
+        }
+
+        self.flush();
+    }
+
+    /// Queue an input that could not be opened
+    fn fail(&mut self, (name, error): (Option<String>, String)) {
+        self.queue.push_back(Pending {
+            result: InputResult {
+                name,
+                counts: None,
+                error: Some(error),
+            },
+            chunks: Some(0),
+            parts: vec![],
+            received: 0,
+        });
+
+        self.flush();
+    }
+
+    /// Collect the chunk results, and print the inputs at the front that are complete
+    fn flush(&mut self) {
+        while let Ok((index, seq, result)) = self.receiver.try_recv() {
+            let pending = &mut self.queue[index - self.printed];
+            if pending.parts.len() <= seq {
+                pending.parts.resize(seq + 1, None);
+            }
+            match result {
+                Ok(counter) => pending.parts[seq] = Some(counter),
+                // the counts read before the failure are still reported
+                Err(e) => pending.result.error = Some(e.to_string()),
+            }
+            pending.received += 1;
+        }
+
+        while let Some(pending) = self.queue.front() {
+            if pending.chunks != Some(pending.received) {
+                break;
+            }
+
+            let mut pending = self.queue.pop_front().unwrap();
+            if let Some(counts) = &mut pending.result.counts {
+                // merge the chunks in order, so their boundaries line up
+                let counter =
+                    pending
+                        .parts
+                        .iter()
+                        .flatten()
+                        .fold(Counter::default(), |mut counter, part| {
+                            counter.merge(part);
+                            counter
+                        });
+                *counts = counter.finish();
+            }
+
+            self.print(pending.result);
+            self.printed += 1;
+        }
+    }
+
+    /// Print the remaining inputs and the total, after all jobs are done
+    ///
+    /// Returns if any input failed
+    fn finish(mut self) -> bool {
+        self.flush();
+        assert!(self.queue.is_empty(), "results of all chunks are received");
+
+        let total = self.total.values();
+        match self.format {
+            Format::Text { .. } if self.printed > 1 => self.print_row(total, Some("total")),
+            Format::Text { .. } => (),
+            Format::Json => {
+                if self.printed == 0 {
+                    print!("{{\"inputs\":[");
+                }
+                println!(
+                    "\n],\"total\":{{{}}}}}",
+                    self.json_counts(Some(&self.total))
+                );
+            }
+        }
+
+        self.failed
+    }
+
+    fn print(&mut self, result: InputResult) {
+        if result.error.is_some() {
+            self.failed = true;
+        }
+        if let Some(counts) = &result.counts {
+            self.total.merge(counts);
+        }
+
+        match self.format {
+            Format::Text { show_names, .. } => {
+                match (&result.name, &result.error) {
+                    (Some(name), Some(error)) => eprintln!("wc: {}: {}", name, error),
+                    (None, Some(error)) => eprintln!("wc: {}", error),
+                    (_, None) => (),
+                }
+                if let Some(counts) = &result.counts {
+                    let name = result.name.as_deref().filter(|_| show_names);
+                    self.print_row(counts.values(), name);
+                }
+            }
+            Format::Json => {
+                let separator = if self.printed == 0 {
+                    "{\"inputs\":[\n"
+                } else {
+                    ",\n"
                 };
-                executor.submit(job);
-            });
+                print!(
+                    "{}{{\"name\":{},{},\"error\":{}}}",
+                    separator,
+                    json_string(result.name.as_deref()),
+                    self.json_counts(result.counts.as_ref()),
+                    json_string(result.error.as_deref()),
+                );
+            }
+        }
+    }
+
+    fn print_row(&self, values: [u64; 5], name: Option<&str>) {
+        let width = match self.format {
+            Format::Text { width, .. } => width,
+            Format::Json => unreachable!(),
+        };
+
+        let mut row = values
+            .iter()
+            .zip(&self.columns)
+            .filter(|(_, show)| **show)
+            .map(|(value, _)| format!("{:>width$}", value, width = width))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some(name) = name {
+            row.push(' ');
+            row.push_str(name);
+        }
+        println!("{}", row);
+    }
+
+    /// JSON fields of the counts, quantities that are not counted are null
+    fn json_counts(&self, counts: Option<&Counts>) -> String {
+        let values = counts.map(Counts::values);
+        let value = |column: usize| match values {
+            Some(values) if self.columns[column] => values[column].to_string(),
+            _ => "null".to_string(),
+        };
+
+        format!(
+            "\"bytes\":{},\"chars\":{},\"words\":{},\"lines\":{},\"max_line_length\":{}",
+            value(3),
+            value(2),
+            value(1),
+            value(0),
+            value(4)
+        )
+    }
+}
+
+/// Quote a string for JSON, or null
+fn json_string(s: Option<&str>) -> String {
+    let s = match s {
+        Some(s) => s,
+        None => return "null".to_string(),
+    };
+
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuple(counts: Counts) -> (u64, u64, u64, u64, u64) {
+        let Counts {
+            lines,
+            words,
+            chars,
+            bytes,
+            max_line_length,
+        } = counts;
+        (bytes, chars, words, lines, max_line_length)
+    }
+
+    fn count(data: &[u8]) -> (u64, u64, u64, u64, u64) {
+        let mut counter = Counter::default();
+        counter.update(data, true);
+        tuple(counter.finish())
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(count(b""), (0, 0, 0, 0, 0));
+        assert_eq!(count(b"hello world\nfoo\n"), (16, 16, 3, 2, 11));
+        assert_eq!(count(b"a\tb\r12\x0c1\n"), (9, 9, 4, 1, 9));
+        assert_eq!(count("日本 語\n".as_bytes()), (11, 5, 2, 1, 7));
+    }
+
+    #[test]
+    fn test_count_newlines() {
+        assert_eq!(count_newlines(b""), 0);
+        assert_eq!(count_newlines(b"a\nb\n\n"), 3);
+
+        // more newlines than fit a lane, and a remainder
+        let data = vec![b'\n'; 32 * 300 + 7];
+        assert_eq!(count_newlines(&data), data.len() as u64);
+
+        let data: Vec<u8> = (0..100_000)
+            .map(|i| if i % 3 == 0 { b'\n' } else { b'x' })
+            .collect();
+        assert_eq!(count_newlines(&data), 33_334);
+    }
+
+    #[test]
+    fn test_count_invalid() {
+        // invalid bytes count as bytes, and do not split or start words
+        assert_eq!(count(b"ab\xffcd\n"), (6, 5, 1, 1, 4));
+        assert_eq!(count(b"\xff \xff\n"), (4, 2, 0, 1, 1));
+        assert_eq!(count(b"a\xe6\x97\nb"), (5, 3, 2, 1, 1));
+        assert_eq!(count(b"\xed\xa0\x80"), (3, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_count_whitespace() {
+        assert_eq!(count("a\u{a0}b\u{3000}c".as_bytes()).2, 3);
+        assert_eq!(count("a\u{2028}b\u{85}c".as_bytes()).2, 1);
+        assert_eq!(count(b"a\x01b\x1cc").2, 1);
+    }
+
+    #[test]
+    fn test_segment() {
+        let mut segment = Segment::default();
+        segment.push(3);
+        assert_eq!(segment.end(0), 3);
+        assert_eq!(segment.end(6), 9);
+
+        let segment = segment.then(Segment::TAB);
+        assert_eq!(segment.end(0), 8);
+        assert_eq!(segment.end(6), 16);
+
+        let segment = Segment::TAB.then(Segment {
+            head: 9,
+            tail: Some(2),
         });
+        assert_eq!(segment.end(0), 26);
+        assert_eq!(segment.end(7), 26);
+    }
 
-    executor.finish();
+    #[test]
+    fn test_merge() {
+        let data = "ab\tc d\n\tefg\x01\x01 hi\n\n\r  jk\u{a0}\u{3000}lm\tn\x0cop \x01q\n\t日r";
+        let expected = count(data.as_bytes());
+
+        // split in three parts, on char boundaries
+        let splits: Vec<_> = data
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(Some(data.len()))
+            .collect();
+        for (i, split) in splits.iter().enumerate() {
+            for split2 in &splits[i..] {
+                let mut merged = Counter::default();
+                for part in &[&data[..*split], &data[*split..*split2], &data[*split2..]] {
+                    let mut counter = Counter::default();
+                    counter.update(part.as_bytes(), true);
+                    merged.merge(&counter);
+                }
+                assert_eq!(tuple(merged.finish()), expected);
+            }
+        }
+    }
 
-    if show_lines {
-        print!("l:{} ", lines_total.load(Ordering::Relaxed));
+    #[test]
+    fn test_delimiter() {
+        let mut counter = Counter::default();
+        counter.update(b"abc\tx", true);
+        counter.merge(&Counter::delimiter());
+        assert_eq!(tuple(counter.finish()), count(b"abc\tx\n"));
     }
-    if show_words {
-        print!("w:{} ", words_total.load(Ordering::Relaxed));
+
+    #[test]
+    fn test_count_split_char() {
+        let data = "x日本".as_bytes();
+        for split in 0..data.len() {
+            let mut counter = Counter::default();
+            let consumed = counter.update(&data[..split], false);
+            let rest = data[consumed..].to_vec();
+            counter.update(&rest, true);
+            assert_eq!(tuple(counter.finish()), (7, 3, 1, 0, 5));
+        }
+
+        let mut counter = Counter::default();
+        counter.update_read(data[..2].to_vec(), &data[2..]).unwrap();
+        assert_eq!(tuple(counter.finish()), (7, 3, 1, 0, 5));
     }
-    if show_chars {
-        print!("c:{} ", chars_total.load(Ordering::Relaxed));
+
+    struct Broken;
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
     }
-    if show_bytes {
-        print!("b:{} ", bytes_total.load(Ordering::Relaxed));
+
+    #[test]
+    fn test_count_read_error() {
+        let mut counter = Counter::default();
+        let error = counter.update_read(b"a b".to_vec(), Broken).unwrap_err();
+        assert_eq!(error.to_string(), "broken");
     }
-    if show_max_line_length {
-        print!("b:{} ", max_line_length.load(Ordering::Relaxed));
+
+    #[test]
+    fn test_count_bytes_read() {
+        let data = b"ab\ncd\ne";
+        assert_eq!(
+            count_bytes_read(data[..4].to_vec(), &data[4..]).unwrap(),
+            (7, 2)
+        );
+
+        let error = count_bytes_read(b"a\n".to_vec(), Broken).unwrap_err();
+        assert_eq!(error.to_string(), "broken");
     }
-    println!()
 }
@@ -71,6 +71,8 @@ pub struct ChunkedReader<R> {
     max_chunk_size: usize,
     /// Internal buffer for splitting
     next_buf: Vec<u8>,
+    /// The last chunk was followed by a delimiter
+    delimited: bool,
 }
 
 impl<R: Read> ChunkedReader<R> {
@@ -92,8 +94,17 @@ impl<R: Read> ChunkedReader<R> {
             delimiter,
             max_chunk_size,
             next_buf: vec![],
+            delimited: false,
         }
     }
+
+    /// Whether the last chunk was followed by the delimiter
+    ///
+    /// The delimiter is not included in the chunk itself, so this tells if it was present at the
+    /// end of the input.
+    pub fn delimited(&self) -> bool {
+        self.delimited
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -118,6 +129,8 @@ impl<R: Read + Send> Iterator for ChunkedReader<R> {
         let mut pos = buf.len();
         buf.resize(self.max_chunk_size, 0);
 
+        self.delimited = false;
+
         // Keep reading until the buffer is full
         let exhausted = loop {
             match input.read(&mut buf[pos..]) {
@@ -142,6 +155,7 @@ impl<R: Read + Send> Iterator for ChunkedReader<R> {
             // remove trailing delimiter, if present
             if buf.last() == Some(&self.delimiter) {
                 buf.pop();
+                self.delimited = true;
             }
 
             return Some(Ok(ChunkedItem::Chunk(buf)));
@@ -158,6 +172,7 @@ impl<R: Read + Send> Iterator for ChunkedReader<R> {
                 // store this chunk for the next run
                 self.next_buf = buf.split_off(pos + 1);
                 buf.pop(); // remove the delimiter
+                self.delimited = true;
 
                 // put back our input attribute
                 self.input = Some(input);
@@ -223,6 +238,23 @@ mod tests {
         assert!(reader.next().is_none());
     }
 
+    #[test]
+    fn test_delimited() {
+        let data = [1u8, 0, 1, 1];
+        let mut reader = ChunkedReader::new(data.as_ref(), 0x0, 3);
+
+        assert_eq!(reader.next().unwrap().unwrap(), Chunk(vec![1]));
+        assert!(reader.delimited());
+        assert_eq!(reader.next().unwrap().unwrap(), Chunk(vec![1, 1]));
+        assert!(!reader.delimited());
+
+        let data = [1u8, 0];
+        let mut reader = ChunkedReader::new(data.as_ref(), 0x0, 3);
+
+        assert_eq!(reader.next().unwrap().unwrap(), Chunk(vec![1]));
+        assert!(reader.delimited());
+    }
+
     #[test]
     fn test_delim_at_start() {
         let data = [0u8, 1, 1, 1, 1, 1, 1, 1, 1, 1];